@@ -0,0 +1,261 @@
+use anyhow::{Context, Error};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// The temporaries currently being written, so they can be removed if interrupted
+static TEMPORARIES: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// An output being written to a temporary sibling, which is only renamed into place once
+/// committed and is removed otherwise
+#[derive(Debug)]
+pub(crate) struct Partial {
+    path: PathBuf,
+    output: PathBuf,
+}
+
+/// Removes the file or directory at the path, if there is one, including read-only ones
+#[culpa::throws]
+#[fn_error_context::context("removing {}", path.display())]
+pub(crate) fn remove(path: &Path) {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path)
+            .or_else(|_| make_writable(path).and_then(|()| Ok(std::fs::remove_dir_all(path)?)))?,
+        Ok(_) => std::fs::remove_file(path)
+            .or_else(|_| make_writable(path).and_then(|()| Ok(std::fs::remove_file(path)?)))?,
+        Err(_) => {}
+    }
+}
+
+/// Gives the owner write permission throughout the tree, e.g. one extracted with --read-only
+#[culpa::throws]
+fn make_writable(path: &Path) {
+    let metadata = path.symlink_metadata()?;
+    if metadata.is_symlink() {
+        return;
+    }
+    let mut permissions = metadata.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        permissions.set_mode(permissions.mode() | 0o200);
+    }
+    #[cfg(not(unix))]
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    std::fs::set_permissions(path, permissions)?;
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            make_writable(&entry?.path())?;
+        }
+    }
+}
+
+/// A hidden sibling of the output, unique to this process
+#[culpa::throws]
+fn sibling(output: &Path, suffix: &str) -> PathBuf {
+    let name = output.file_name().context("output has no file name")?;
+    output.with_file_name(format!(
+        ".{}.{}.{suffix}",
+        name.to_string_lossy(),
+        std::process::id()
+    ))
+}
+
+impl Partial {
+    #[culpa::throws]
+    #[fn_error_context::context("preparing to write {}", output.display())]
+    pub(crate) fn new(output: &Path) -> Self {
+        crate::interrupt::checkpoint();
+        let parent = output.parent().unwrap_or(Path::new(""));
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let path = sibling(output, "partial")?;
+        remove(&path)?;
+        TEMPORARIES.lock().unwrap().insert(path.clone());
+        Self {
+            path,
+            output: output.to_owned(),
+        }
+    }
+
+    /// Where the output should be written to
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Renames the written temporary into place
+    #[culpa::throws]
+    #[fn_error_context::context("renaming {} to {}", self.path.display(), self.output.display())]
+    pub(crate) fn commit(self) {
+        std::fs::rename(&self.path, &self.output)?;
+    }
+
+    /// Renames the written temporary into place, replacing any existing output.
+    ///
+    /// A file is replaced by renaming over it, a directory is first moved aside and only removed
+    /// once the new one is in place, so that the old output is kept if anything fails. Both
+    /// renames happen with the temporaries locked, so an interrupt cannot happen between them.
+    #[culpa::throws]
+    #[fn_error_context::context("replacing {} with {}", self.output.display(), self.path.display())]
+    pub(crate) fn replace(self) {
+        let temporaries = TEMPORARIES.lock().unwrap();
+        if !self
+            .output
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.is_dir())
+        {
+            std::fs::rename(&self.path, &self.output)?;
+            return;
+        }
+        let backup = sibling(&self.output, "old")?;
+        remove(&backup)?;
+        std::fs::rename(&self.output, &backup)?;
+        if let Err(e) = std::fs::rename(&self.path, &self.output) {
+            std::fs::rename(&backup, &self.output)?;
+            culpa::throw!(e);
+        }
+        // The backup is now unneeded, so can be removed if interrupted
+        let mut temporaries = temporaries;
+        temporaries.insert(backup.clone());
+        drop(temporaries);
+        let removed = remove(&backup);
+        TEMPORARIES.lock().unwrap().remove(&backup);
+        removed?;
+    }
+
+    /// Moves the files of the written temporary directory into the existing output directory,
+    /// replacing files of the same name
+    #[culpa::throws]
+    #[fn_error_context::context("merging {} into {}", self.path.display(), self.output.display())]
+    pub(crate) fn merge(self) {
+        merge(&self.path, &self.output)?;
+    }
+}
+
+#[culpa::throws]
+fn merge(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() && target.is_dir() {
+            merge(&entry.path(), &target)?;
+        } else {
+            remove(&target)?;
+            std::fs::rename(entry.path(), &target)?;
+        }
+    }
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        let mut temporaries = TEMPORARIES.lock().unwrap();
+        if let Err(e) = remove(&self.path) {
+            tracing::warn!("could not remove {}: {e:#}", self.path.display());
+        }
+        temporaries.remove(&self.path);
+    }
+}
+
+/// Writes the data to the output via a temporary sibling
+#[culpa::throws]
+pub(crate) fn write(output: &Path, data: impl AsRef<[u8]>) {
+    let partial = Partial::new(output)?;
+    std::fs::write(partial.path(), data)
+        .with_context(|| format!("writing {}", partial.path().display()))?;
+    partial.commit()?;
+}
+
+/// Removes all temporaries that are still being written, returning with the lock held so that no
+/// more can be started.
+///
+/// Threads may still be finishing their current write, so this retries until they are gone.
+pub(crate) fn remove_all() -> std::sync::MutexGuard<'static, BTreeSet<PathBuf>> {
+    let temporaries = TEMPORARIES.lock().unwrap_or_else(|e| e.into_inner());
+    for _ in 0..10 {
+        for path in temporaries.iter() {
+            if let Err(e) = remove(path) {
+                tracing::debug!("could not remove {}: {e:#}", path.display());
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        if temporaries
+            .iter()
+            .all(|path| path.symlink_metadata().is_err())
+        {
+            break;
+        }
+    }
+    temporaries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{remove, write, Partial};
+
+    fn siblings(dir: &std::path::Path) -> Vec<String> {
+        let mut names = Vec::from_iter(
+            std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap()),
+        );
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn uncommitted_outputs_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        let partial = Partial::new(&output).unwrap();
+        std::fs::write(partial.path(), "partial").unwrap();
+        drop(partial);
+        assert!(siblings(dir.path()).is_empty());
+
+        write(&output, "done").unwrap();
+        assert_eq!(siblings(dir.path()), ["out"]);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "done");
+    }
+
+    #[test]
+    fn replace_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        std::fs::create_dir_all(output.join("old")).unwrap();
+
+        let partial = Partial::new(&output).unwrap();
+        std::fs::create_dir(partial.path()).unwrap();
+        std::fs::write(partial.path().join("new"), "").unwrap();
+        partial.replace().unwrap();
+        assert_eq!(siblings(dir.path()), ["out"]);
+        assert_eq!(siblings(&output), ["new"]);
+    }
+
+    #[test]
+    fn replace_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        std::fs::write(&output, "old").unwrap();
+
+        let partial = Partial::new(&output).unwrap();
+        std::fs::write(partial.path(), "new").unwrap();
+        partial.replace().unwrap();
+        assert_eq!(siblings(dir.path()), ["out"]);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "new");
+    }
+
+    #[test]
+    fn remove_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        std::fs::create_dir_all(output.join("sub")).unwrap();
+        std::fs::write(output.join("sub/file"), "").unwrap();
+        crate::unpack::make_read_only(&output).unwrap();
+        remove(&output).unwrap();
+        assert!(siblings(dir.path()).is_empty());
+    }
+}
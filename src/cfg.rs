@@ -0,0 +1,292 @@
+use anyhow::{anyhow, Context, Error};
+use std::{ffi::OsString, process::Command};
+
+/// A `cfg(...)` expression as used in platform specific dependencies
+#[derive(Clone, Debug)]
+enum Expr {
+    Name(String),
+    KeyValue(String, String),
+    All(Vec<Expr>),
+    Any(Vec<Expr>),
+    Not(Box<Expr>),
+}
+
+/// The platform dependencies are being resolved for
+#[derive(Clone, Debug)]
+pub(crate) enum Platform {
+    /// Follow platform specific dependencies for every platform
+    All,
+    Target {
+        triple: String,
+        cfgs: Vec<(String, Option<String>)>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    String(String),
+    LeftParen,
+    RightParen,
+    Comma,
+    Equals,
+}
+
+#[culpa::throws]
+fn tokenize(s: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => tokens.push(Token::LeftParen),
+            ')' => tokens.push(Token::RightParen),
+            ',' => tokens.push(Token::Comma),
+            '=' => tokens.push(Token::Equals),
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => value.push(c),
+                        None => culpa::throw!(anyhow!("unterminated string")),
+                    }
+                }
+                tokens.push(Token::String(value));
+            }
+            c if c.is_whitespace() => {}
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut ident = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if !c.is_alphanumeric() && c != '_' {
+                        break;
+                    }
+                    ident.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Ident(ident));
+            }
+            c => culpa::throw!(anyhow!("unexpected character {c:?}")),
+        }
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: std::iter::Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser {
+    #[culpa::throws]
+    fn expect(&mut self, expected: Token) {
+        match self.tokens.next() {
+            Some(token) if token == expected => {}
+            token => culpa::throw!(anyhow!("expected {expected:?} but found {token:?}")),
+        }
+    }
+
+    #[culpa::throws]
+    fn list(&mut self) -> Vec<Expr> {
+        self.expect(Token::LeftParen)?;
+        let mut exprs = Vec::new();
+        while self.tokens.peek() != Some(&Token::RightParen) {
+            exprs.push(self.expr()?);
+            if self.tokens.peek() == Some(&Token::Comma) {
+                self.tokens.next();
+            } else {
+                break;
+            }
+        }
+        self.expect(Token::RightParen)?;
+        exprs
+    }
+
+    #[culpa::throws]
+    fn expr(&mut self) -> Expr {
+        let Some(Token::Ident(ident)) = self.tokens.next() else {
+            culpa::throw!(anyhow!("expected an identifier"));
+        };
+        match (ident.as_str(), self.tokens.peek()) {
+            ("all", Some(Token::LeftParen)) => Expr::All(self.list()?),
+            ("any", Some(Token::LeftParen)) => Expr::Any(self.list()?),
+            ("not", Some(Token::LeftParen)) => {
+                let mut exprs = self.list()?;
+                if exprs.len() != 1 {
+                    culpa::throw!(anyhow!("not() takes exactly one argument"));
+                }
+                Expr::Not(Box::new(exprs.remove(0)))
+            }
+            (_, Some(Token::Equals)) => {
+                self.tokens.next();
+                let Some(Token::String(value)) = self.tokens.next() else {
+                    culpa::throw!(anyhow!("expected a string after `{ident} =`"));
+                };
+                Expr::KeyValue(ident, value)
+            }
+            _ => Expr::Name(ident),
+        }
+    }
+}
+
+impl Expr {
+    #[culpa::throws]
+    #[fn_error_context::context("parsing cfg expression {:?}", s)]
+    fn parse(s: &str) -> Self {
+        let mut parser = Parser {
+            tokens: tokenize(s)?.into_iter().peekable(),
+        };
+        let expr = parser.expr()?;
+        if let Some(token) = parser.tokens.next() {
+            culpa::throw!(anyhow!("unexpected trailing {token:?}"));
+        }
+        expr
+    }
+
+    fn eval(&self, cfgs: &[(String, Option<String>)]) -> bool {
+        match self {
+            Expr::Name(name) => cfgs.iter().any(|(k, v)| k == name && v.is_none()),
+            Expr::KeyValue(key, value) => cfgs
+                .iter()
+                .any(|(k, v)| k == key && v.as_ref() == Some(value)),
+            Expr::All(exprs) => exprs.iter().all(|expr| expr.eval(cfgs)),
+            Expr::Any(exprs) => exprs.iter().any(|expr| expr.eval(cfgs)),
+            Expr::Not(expr) => !expr.eval(cfgs),
+        }
+    }
+}
+
+fn rustc() -> Command {
+    Command::new(std::env::var_os("RUSTC").unwrap_or_else(|| OsString::from("rustc")))
+}
+
+#[culpa::throws]
+fn output(mut command: Command) -> String {
+    let output = command
+        .output()
+        .with_context(|| format!("failed to run {command:?}"))?;
+    if !output.status.success() {
+        culpa::throw!(anyhow!(
+            "{command:?} failed with {}:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    String::from_utf8(output.stdout)?
+}
+
+impl Platform {
+    /// Queries rustc for the cfg values of the target, defaulting to the host
+    #[culpa::throws]
+    #[fn_error_context::context("getting cfg values for target {}", triple.unwrap_or("host"))]
+    pub(crate) fn target(triple: Option<&str>) -> Self {
+        let triple = match triple {
+            Some(triple) => triple.to_owned(),
+            None => {
+                let mut command = rustc();
+                command.arg("-vV");
+                output(command)?
+                    .lines()
+                    .find_map(|line| line.strip_prefix("host: "))
+                    .context("rustc did not report its host")?
+                    .to_owned()
+            }
+        };
+        let mut command = rustc();
+        command.args(["--print=cfg", "--target", &triple]);
+        let cfgs = output(command)?
+            .lines()
+            .map(|line| match line.split_once('=') {
+                Some((key, value)) => (key.to_owned(), Some(value.trim_matches('"').to_owned())),
+                None => (line.to_owned(), None),
+            })
+            .collect();
+        Platform::Target { triple, cfgs }
+    }
+
+    /// Whether a dependency with this `target` applies to the platform
+    #[culpa::throws]
+    pub(crate) fn matches(&self, target: &str) -> bool {
+        match self {
+            Platform::All => true,
+            Platform::Target { triple, cfgs } => match target
+                .strip_prefix("cfg(")
+                .and_then(|cfg| cfg.strip_suffix(')'))
+            {
+                Some(cfg) => Expr::parse(cfg)?.eval(cfgs),
+                None => target == triple,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tokenize, Expr, Platform, Token};
+
+    fn linux() -> Platform {
+        Platform::Target {
+            triple: "x86_64-unknown-linux-gnu".to_owned(),
+            cfgs: vec![
+                ("unix".to_owned(), None),
+                ("debug_assertions".to_owned(), None),
+                ("target_os".to_owned(), Some("linux".to_owned())),
+                ("target_pointer_width".to_owned(), Some("64".to_owned())),
+            ],
+        }
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            tokenize(r#"all(unix, target_os = "linux")"#).unwrap(),
+            [
+                Token::Ident("all".to_owned()),
+                Token::LeftParen,
+                Token::Ident("unix".to_owned()),
+                Token::Comma,
+                Token::Ident("target_os".to_owned()),
+                Token::Equals,
+                Token::String("linux".to_owned()),
+                Token::RightParen,
+            ]
+        );
+        assert!(tokenize(r#"target_os = "linux"#).is_err());
+        assert!(tokenize("unix && windows").is_err());
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for s in [
+            "",
+            "all(unix",
+            "not(unix, windows)",
+            "unix windows",
+            "target_os = linux",
+            "(unix)",
+        ] {
+            assert!(Expr::parse(s).is_err(), "{s:?} should not parse");
+        }
+    }
+
+    #[test]
+    fn matching() {
+        let platform = linux();
+        for (target, expected) in [
+            ("cfg(unix)", true),
+            ("cfg(windows)", false),
+            (r#"cfg(target_os = "linux")"#, true),
+            (r#"cfg(target_os = "macos")"#, false),
+            ("cfg(target_os)", false),
+            (r#"cfg(all(unix, target_pointer_width = "64"))"#, true),
+            (r#"cfg(all(unix, target_pointer_width = "32"))"#, false),
+            ("cfg(any(windows, unix,))", true),
+            ("cfg(any())", false),
+            ("cfg(all())", true),
+            ("cfg(not(windows))", true),
+            ("x86_64-unknown-linux-gnu", true),
+            ("x86_64-pc-windows-msvc", false),
+        ] {
+            assert_eq!(platform.matches(target).unwrap(), expected, "{target}");
+        }
+        assert!(Platform::All.matches("cfg(windows)").unwrap());
+    }
+}
@@ -0,0 +1,425 @@
+use crate::{
+    cfg::Platform, index_cache::Registries, package_id_spec::registry_url, version::Version,
+};
+use anyhow::{anyhow, Context, Error};
+use crates_index::DependencyKind;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Controls which dependencies are followed while resolving
+pub(crate) struct Options<'a> {
+    pub(crate) dev: bool,
+    pub(crate) features: &'a [String],
+    pub(crate) all_features: bool,
+    pub(crate) no_default_features: bool,
+    pub(crate) platform: &'a Platform,
+}
+
+/// A selected version along with the features that have been requested of it
+struct Node {
+    /// The index url of the registry the version is from
+    registry: String,
+    version: crates_index::Version,
+    features: BTreeSet<String>,
+    root: bool,
+}
+
+/// The optional dependencies enabled and the features requested of each dependency by a set of
+/// features
+#[derive(Default)]
+struct Activated {
+    dependencies: BTreeSet<String>,
+    dependency_features: BTreeMap<String, BTreeSet<String>>,
+}
+
+fn is_optional_dependency(version: &crates_index::Version, name: &str) -> bool {
+    version
+        .dependencies()
+        .iter()
+        .any(|dep| dep.is_optional() && dep.name() == name)
+}
+
+/// Expands the requested features through the crate's feature table, the same as cargo's
+/// feature resolver does including `dep:` and weak `dep?/feature` syntax
+fn activate(version: &crates_index::Version, requested: &BTreeSet<String>) -> Activated {
+    let mut activated = Activated::default();
+    let mut seen = BTreeSet::new();
+    let mut stack = Vec::from_iter(requested.iter().cloned());
+    while let Some(feature) = stack.pop() {
+        if !seen.insert(feature.clone()) {
+            continue;
+        }
+        let Some(values) = version.features().get(&feature) else {
+            if is_optional_dependency(version, &feature) {
+                activated.dependencies.insert(feature);
+            } else if feature != "default" {
+                tracing::debug!(
+                    "{} {} has no feature {feature}",
+                    version.name(),
+                    version.version()
+                );
+            }
+            continue;
+        };
+        for value in values {
+            if let Some(dep) = value.strip_prefix("dep:") {
+                activated.dependencies.insert(dep.to_owned());
+            } else if let Some((dep, dep_feature)) = value.split_once('/') {
+                let dep = match dep.strip_suffix('?') {
+                    Some(dep) => dep,
+                    None => {
+                        stack.push(dep.to_owned());
+                        dep
+                    }
+                };
+                activated
+                    .dependency_features
+                    .entry(dep.to_owned())
+                    .or_default()
+                    .insert(dep_feature.to_owned());
+            } else {
+                stack.push(value.clone());
+            }
+        }
+    }
+    activated
+}
+
+/// Picks the newest non-yanked version matching the requirement
+fn select(krate: &crates_index::Crate, req: &semver::VersionReq) -> Option<crates_index::Version> {
+    krate
+        .versions()
+        .iter()
+        .filter(|version| !version.is_yanked())
+        .filter_map(|version| Some((version.version().parse().ok()?, version)))
+        .filter(|(num, _)| req.matches(num))
+        .max_by(|(a, _): &(semver::Version, _), (b, _)| a.cmp(b))
+        .map(|(_, version)| version.clone())
+}
+
+/// Resolves the transitive dependencies of the root versions from index data, reusing an already
+/// selected version of a crate whenever it satisfies a requirement, roughly like cargo's resolver.
+///
+/// Versions are paired with the index url of their registry, dependencies from other registries
+/// are looked up in those, as given by `registries` for each crate.
+#[culpa::throws]
+pub(crate) fn resolve(
+    roots: &[(String, crates_index::Version)],
+    options: &Options<'_>,
+    mut lookup: impl FnMut(&str, &str) -> Result<Option<crates_index::Crate>, Error>,
+    mut registries: impl FnMut(&str, &str) -> Result<Registries, Error>,
+) -> Vec<(String, crates_index::Version)> {
+    let mut crates = HashMap::new();
+    let mut crate_registries = HashMap::new();
+    let mut nodes = Vec::from_iter(roots.iter().map(|(registry, version)| {
+        let mut features = BTreeSet::from_iter(options.features.iter().cloned());
+        if !options.no_default_features {
+            features.insert("default".to_owned());
+        }
+        if options.all_features {
+            features.extend(version.features().keys().cloned());
+            features.extend(
+                version
+                    .dependencies()
+                    .iter()
+                    .filter(|dep| dep.is_optional())
+                    .map(|dep| dep.name().to_owned()),
+            );
+        }
+        Node {
+            registry: registry.clone(),
+            version: version.clone(),
+            features,
+            root: true,
+        }
+    }));
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let mut i = 0;
+        while i < nodes.len() {
+            let activated = activate(&nodes[i].version, &nodes[i].features);
+            let parent = nodes[i].version.clone();
+            let parent_registry = nodes[i].registry.clone();
+            let key = (parent_registry.clone(), parent.name().to_owned());
+            if !crate_registries.contains_key(&key) {
+                let found = registries(&parent_registry, parent.name())?;
+                crate_registries.insert(key.clone(), found);
+            }
+            let dep_registries = &crate_registries[&key];
+            for dep in parent.dependencies() {
+                let follow = match dep.kind() {
+                    DependencyKind::Normal | DependencyKind::Build => true,
+                    DependencyKind::Dev => nodes[i].root && options.dev,
+                };
+                if !follow || dep.is_optional() && !activated.dependencies.contains(dep.name()) {
+                    continue;
+                }
+                if let Some(target) = dep.target() {
+                    match options.platform.matches(target) {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(e) => {
+                            tracing::warn!(
+                                "skipping {} of {} {}, could not evaluate its target {target}: {e:#}",
+                                dep.crate_name(),
+                                parent.name(),
+                                parent.version()
+                            );
+                            continue;
+                        }
+                    }
+                }
+
+                let req: semver::VersionReq = dep.requirement().parse().with_context(|| {
+                    format!(
+                        "invalid requirement {} on {} in {} {}",
+                        dep.requirement(),
+                        dep.crate_name(),
+                        parent.name(),
+                        parent.version()
+                    )
+                })?;
+                let mut features = BTreeSet::from_iter(dep.features().iter().cloned());
+                features.extend(
+                    activated
+                        .dependency_features
+                        .get(dep.name())
+                        .into_iter()
+                        .flatten()
+                        .cloned(),
+                );
+                if dep.has_default_features() {
+                    features.insert("default".to_owned());
+                }
+
+                // Without a registry, the dependency is from the same registry as its parent
+                let registry = match dep_registries
+                    .get(&(parent.version().to_owned(), dep.name().to_owned()))
+                {
+                    Some(url) => registry_url(url),
+                    None => parent_registry.clone(),
+                };
+                let existing = nodes.iter().position(|node| {
+                    node.registry == registry
+                        && node.version.name() == dep.crate_name()
+                        && matches!(Version::parse(node.version.version()), Version::Semver(num) if req.matches(&num))
+                });
+                let index = match existing {
+                    Some(index) => index,
+                    None => {
+                        let key = (registry.clone(), dep.crate_name().to_owned());
+                        if !crates.contains_key(&key) {
+                            let krate =
+                                lookup(&registry, dep.crate_name())?.with_context(|| {
+                                    format!(
+                                    "could not find {} (required by {} {}) in the index {registry}",
+                                    dep.crate_name(),
+                                    parent.name(),
+                                    parent.version()
+                                )
+                                })?;
+                            crates.insert(key.clone(), krate);
+                        }
+                        let version = select(&crates[&key], &req).ok_or_else(|| {
+                            anyhow!(
+                                "no version of {} matches {req} (required by {} {})",
+                                dep.crate_name(),
+                                parent.name(),
+                                parent.version()
+                            )
+                        })?;
+                        tracing::debug!(
+                            "selected {} {} for {} {}",
+                            version.name(),
+                            version.version(),
+                            parent.name(),
+                            parent.version()
+                        );
+                        nodes.push(Node {
+                            registry,
+                            version,
+                            features: BTreeSet::new(),
+                            root: false,
+                        });
+                        changed = true;
+                        nodes.len() - 1
+                    }
+                };
+                for feature in features {
+                    changed |= nodes[index].features.insert(feature);
+                }
+            }
+            i += 1;
+        }
+    }
+
+    nodes
+        .into_iter()
+        .filter(|node| !node.root)
+        .map(|node| (node.registry, node.version))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, Options};
+    use crate::{cfg::Platform, index_cache::Registries};
+    use anyhow::Error;
+    use std::collections::HashMap;
+
+    const MAIN: &str = "sparse+https://main.example/";
+    const OTHER: &str = "sparse+https://other.example/";
+
+    fn entry(
+        name: &str,
+        vers: &str,
+        deps: &[(&str, &str, &str, bool, Option<&str>)],
+        features: serde_json::Value,
+    ) -> String {
+        serde_json::json!({
+            "name": name,
+            "vers": vers,
+            "deps": Vec::from_iter(deps.iter().map(|&(name, req, kind, optional, target)| serde_json::json!({
+                "name": name,
+                "req": req,
+                "features": [],
+                "optional": optional,
+                "default_features": true,
+                "target": target,
+                "kind": kind,
+            }))),
+            "cksum": "0000000000000000000000000000000000000000000000000000000000000000",
+            "features": features,
+            "yanked": false,
+        })
+        .to_string()
+    }
+
+    fn index() -> HashMap<(&'static str, &'static str), String> {
+        let no_features = serde_json::json!({});
+        HashMap::from([
+            (
+                (MAIN, "app"),
+                entry(
+                    "app",
+                    "0.1.0",
+                    &[
+                        ("foo", "^1.0", "normal", false, None),
+                        ("test", "^1", "dev", false, None),
+                        ("qux", "^0.3", "normal", true, None),
+                        ("winonly", "^1", "normal", false, Some("cfg(windows)")),
+                        (
+                            "unparseable",
+                            "^1",
+                            "normal",
+                            false,
+                            Some("cfg(unix windows)"),
+                        ),
+                        ("remote", "^1", "normal", false, None),
+                    ],
+                    serde_json::json!({ "extra": ["dep:qux"] }),
+                ),
+            ),
+            (
+                (MAIN, "foo"),
+                [
+                    entry("foo", "1.0.0", &[], no_features.clone()),
+                    entry("foo", "1.2.0", &[], no_features.clone()),
+                    entry("foo", "2.0.0", &[], no_features.clone()),
+                ]
+                .join("\n"),
+            ),
+            (
+                (MAIN, "test"),
+                entry("test", "1.0.0", &[], no_features.clone()),
+            ),
+            (
+                (MAIN, "qux"),
+                entry(
+                    "qux",
+                    "0.3.1",
+                    &[("foo", "^1.2", "build", false, None)],
+                    no_features.clone(),
+                ),
+            ),
+            (
+                (MAIN, "winonly"),
+                entry("winonly", "1.0.0", &[], no_features.clone()),
+            ),
+            (
+                (MAIN, "unparseable"),
+                entry("unparseable", "1.0.0", &[], no_features.clone()),
+            ),
+            (
+                (OTHER, "remote"),
+                entry("remote", "1.0.0", &[], no_features),
+            ),
+        ])
+    }
+
+    fn run(features: &[String], dev: bool) -> Vec<String> {
+        let index = index();
+        let platform = Platform::Target {
+            triple: "x86_64-unknown-linux-gnu".to_owned(),
+            cfgs: vec![("unix".to_owned(), None)],
+        };
+        let options = Options {
+            dev,
+            features,
+            all_features: false,
+            no_default_features: false,
+            platform: &platform,
+        };
+        let root = crates_index::Crate::from_slice(index[&(MAIN, "app")].as_bytes())
+            .unwrap()
+            .versions()[0]
+            .clone();
+        let lookup = |registry: &str, name: &str| -> Result<_, Error> {
+            Ok(index
+                .get(&(registry, name))
+                .map(|entry| crates_index::Crate::from_slice(entry.as_bytes()).unwrap()))
+        };
+        let registries = |_: &str, name: &str| -> Result<_, Error> {
+            let mut registries = Registries::new();
+            if name == "app" {
+                registries.insert(("0.1.0".to_owned(), "remote".to_owned()), OTHER.to_owned());
+            }
+            Ok(registries)
+        };
+        let mut resolved = Vec::from_iter(
+            resolve(&[(MAIN.to_owned(), root)], &options, lookup, registries)
+                .unwrap()
+                .into_iter()
+                .map(|(registry, version)| {
+                    format!("{registry}#{}@{}", version.name(), version.version())
+                }),
+        );
+        resolved.sort();
+        resolved
+    }
+
+    #[test]
+    fn default_features() {
+        assert_eq!(
+            run(&[], false),
+            [
+                "sparse+https://main.example/#foo@1.2.0",
+                "sparse+https://other.example/#remote@1.0.0",
+            ]
+        );
+    }
+
+    #[test]
+    fn optional_and_dev_dependencies() {
+        assert_eq!(
+            run(&["extra".to_owned()], true),
+            [
+                "sparse+https://main.example/#foo@1.2.0",
+                "sparse+https://main.example/#qux@0.3.1",
+                "sparse+https://main.example/#test@1.0.0",
+                "sparse+https://other.example/#remote@1.0.0",
+            ]
+        );
+    }
+}
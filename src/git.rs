@@ -0,0 +1,273 @@
+use crate::{
+    package_id_spec::{GitReference, GitSource},
+    version::{Version, VersionReq},
+};
+use anyhow::{anyhow, Context, Error};
+use std::{ffi::OsString, path::Path, process::Command};
+
+#[culpa::throws]
+fn run(mut command: Command) -> Vec<u8> {
+    tracing::debug!("running {command:?}");
+    let output = command
+        .output()
+        .with_context(|| format!("failed to run {command:?}"))?;
+    if !output.status.success() {
+        culpa::throw!(anyhow!(
+            "{command:?} failed with {}:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    output.stdout
+}
+
+fn git(dir: &Path) -> Command {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(dir);
+    command
+}
+
+fn cargo() -> Command {
+    Command::new(std::env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo")))
+}
+
+#[culpa::throws]
+#[fn_error_context::context("checking out {}", source.url)]
+fn checkout(source: &GitSource, dir: &Path) {
+    if source.url.starts_with('-') {
+        culpa::throw!(anyhow!("invalid git URL {}", source.url));
+    }
+    if let Some(GitReference::Rev(rev)) = &source.reference {
+        if rev.starts_with('-') {
+            culpa::throw!(anyhow!("invalid git revision {rev}"));
+        }
+    }
+    let mut command = Command::new("git");
+    command
+        .args(["clone", "--quiet", "--", &source.url])
+        .arg(dir);
+    run(command)?;
+
+    let target = match &source.reference {
+        None => None,
+        Some(GitReference::Rev(rev)) => {
+            // The rev may not be reachable from the refs the clone fetched, e.g. a commit of an
+            // unmerged pull request, but abbreviated revs can only be found locally
+            let mut command = git(dir);
+            command.args(["fetch", "--quiet", "--", "origin", rev]);
+            match run(command) {
+                Ok(_) => Some("FETCH_HEAD".to_owned()),
+                Err(e) => {
+                    tracing::debug!("could not fetch {rev}, looking for it in the clone: {e:#}");
+                    Some(rev.clone())
+                }
+            }
+        }
+        Some(GitReference::Branch(branch)) => Some(format!("origin/{branch}")),
+        Some(GitReference::Tag(tag)) => Some(format!("tags/{tag}")),
+    };
+    if let Some(target) = target {
+        let mut command = git(dir);
+        // `checkout` treats anything after `--` as a path, `switch` only takes a revision so its
+        // options can be ended before the target
+        command.args(["switch", "--quiet", "--detach", "--end-of-options", &target]);
+        run(command)?;
+    }
+
+    let mut command = git(dir);
+    command.args(["submodule", "update", "--quiet", "--init", "--recursive"]);
+    run(command)?;
+}
+
+/// Finds the version of the named package within the workspace at `dir`
+#[culpa::throws]
+#[fn_error_context::context("locating package {} in workspace", name)]
+fn locate(dir: &Path, name: &str) -> semver::Version {
+    let mut command = cargo();
+    command
+        .args([
+            "metadata",
+            "--no-deps",
+            "--format-version=1",
+            "--manifest-path",
+        ])
+        .arg(dir.join("Cargo.toml"));
+    let metadata: serde_json::Value = serde_json::from_slice(&run(command)?)?;
+    let package = metadata["packages"]
+        .as_array()
+        .context("missing packages in cargo metadata")?
+        .iter()
+        .find(|package| package["name"] == name)
+        .context("package not found in workspace")?;
+    package["version"]
+        .as_str()
+        .context("missing package version in cargo metadata")?
+        .parse()?
+}
+
+/// Clones the repository and packages the named crate from its workspace, producing the same
+/// `.crate` file that `cargo package` would
+#[culpa::throws]
+#[fn_error_context::context("packaging {} from {}", name, source.url)]
+pub(crate) fn package(
+    source: &GitSource,
+    name: &str,
+    version_req: Option<&VersionReq>,
+    bar: &indicatif::ProgressBar,
+) -> (semver::Version, Vec<u8>) {
+    let dir = tempfile::Builder::new().prefix("cargo-dl-git-").tempdir()?;
+    let repo = dir.path().join("repo");
+    let target = dir.path().join("target");
+
+    bar.set_message(stylish::ansi::format!("cloning {:(fg=blue)}", source.url));
+    checkout(source, &repo)?;
+
+    bar.set_message(stylish::ansi::format!(
+        "locating {:(fg=magenta)} in workspace",
+        name
+    ));
+    let version = locate(&repo, name)?;
+    if let Some(version_req) = version_req {
+        if !version_req.matches(&Version::Semver(version.clone())) {
+            culpa::throw!(anyhow!(
+                "found version {version} which does not match {version_req}"
+            ));
+        }
+    }
+
+    bar.set_message(stylish::ansi::format!(
+        "packaging {:(fg=magenta)} {:(fg=magenta)}",
+        name,
+        version
+    ));
+    let mut command = cargo();
+    command
+        .args([
+            "package",
+            "--quiet",
+            "--no-verify",
+            "--allow-dirty",
+            "--package",
+            name,
+        ])
+        .arg("--manifest-path")
+        .arg(repo.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target);
+    run(command)?;
+
+    let crate_file = target
+        .join("package")
+        .join(format!("{name}-{version}.crate"));
+    let data = std::fs::read(&crate_file)
+        .with_context(|| format!("reading packaged crate {}", crate_file.display()))?;
+
+    (version, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checkout, git, run};
+    use crate::package_id_spec::{GitReference, GitSource};
+    use std::path::Path;
+
+    fn source(url: &str, reference: Option<GitReference>) -> GitSource {
+        GitSource {
+            url: url.to_owned(),
+            reference,
+        }
+    }
+
+    /// Commits in the repository with a fixed identity, returning the commit's hash
+    fn commit(dir: &Path, message: &str) -> String {
+        let mut command = git(dir);
+        command
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(["commit", "--quiet", "--allow-empty", "-m", message]);
+        run(command).unwrap();
+        let mut command = git(dir);
+        command.args(["rev-parse", "HEAD"]);
+        String::from_utf8(run(command).unwrap())
+            .unwrap()
+            .trim()
+            .to_owned()
+    }
+
+    /// Creates a repository with a single commit
+    fn upstream(dir: &Path) -> String {
+        let upstream = dir.join("upstream");
+        let mut command = git(dir);
+        command.args(["init", "--quiet"]).arg(&upstream);
+        run(command).unwrap();
+        commit(&upstream, "first");
+        format!("file://{}", upstream.display())
+    }
+
+    #[test]
+    fn rejects_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = upstream(dir.path());
+        for source in [
+            source("--upload-pack=touch pwned", None),
+            source(&url, Some(GitReference::Rev("--output=pwned".to_owned()))),
+        ] {
+            let err = checkout(&source, &dir.path().join("repo")).unwrap_err();
+            assert!(format!("{err:#}").contains("invalid git"), "{err:#}");
+        }
+        assert!(!dir.path().join("repo").exists());
+    }
+
+    #[test]
+    fn checks_out_branches() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = upstream(dir.path());
+        let upstream = dir.path().join("upstream");
+        let mut command = git(&upstream);
+        command.args(["checkout", "--quiet", "-b", "feature"]);
+        run(command).unwrap();
+        let head = commit(&upstream, "second");
+
+        let clone = dir.path().join("clone");
+        checkout(
+            &source(&url, Some(GitReference::Branch("feature".to_owned()))),
+            &clone,
+        )
+        .unwrap();
+        let mut command = git(&clone);
+        command.args(["rev-parse", "HEAD"]);
+        assert_eq!(
+            String::from_utf8(run(command).unwrap()).unwrap().trim(),
+            head
+        );
+    }
+
+    #[test]
+    fn unadvertised_revs() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = upstream(dir.path());
+        let upstream = dir.path().join("upstream");
+        let mut command = git(&upstream);
+        command.args(["checkout", "--quiet", "--detach"]);
+        run(command).unwrap();
+        let hidden = commit(&upstream, "hidden");
+        let mut command = git(&upstream);
+        command.args(["update-ref", "refs/hidden/x", &hidden]);
+        run(command).unwrap();
+        let mut command = git(&upstream);
+        command.args(["checkout", "--quiet", "-"]);
+        run(command).unwrap();
+
+        let clone = dir.path().join("clone");
+        checkout(
+            &source(&url, Some(GitReference::Rev(hidden.clone()))),
+            &clone,
+        )
+        .unwrap();
+        let mut command = git(&clone);
+        command.args(["rev-parse", "HEAD"]);
+        assert_eq!(
+            String::from_utf8(run(command).unwrap()).unwrap().trim(),
+            hidden
+        );
+    }
+}
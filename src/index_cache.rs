@@ -0,0 +1,131 @@
+use anyhow::Error;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+/// The index entries of crates as the registries served them during this run, keyed by the
+/// registry url and lowercased crate name.
+///
+/// `crates_index` re-serializes the entries it parses and caches, which drops fields it does not
+/// know about, like the registry of dependencies or `features2`.
+static ENTRIES: Mutex<BTreeMap<(String, String), Vec<u8>>> = Mutex::new(BTreeMap::new());
+
+/// The registries of the dependencies that are not from the same registry as the crate, keyed by
+/// the version and the name of the dependency
+pub(crate) type Registries = HashMap<(String, String), String>;
+
+#[derive(serde::Deserialize)]
+struct Entry {
+    vers: String,
+    #[serde(default)]
+    deps: Vec<Dependency>,
+}
+
+#[derive(serde::Deserialize)]
+struct Dependency {
+    name: String,
+    registry: Option<String>,
+}
+
+/// Keeps the index entry of a crate as the registry served it
+pub(crate) fn record(url: &str, name: &str, body: &[u8]) {
+    ENTRIES
+        .lock()
+        .unwrap()
+        .insert((url.to_owned(), name.to_lowercase()), body.to_owned());
+}
+
+/// The registries of dependencies in an index entry
+#[culpa::throws]
+fn parse(body: &[u8]) -> Registries {
+    let mut registries = Registries::new();
+    for line in body.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+        let entry: Entry = serde_json::from_slice(line)?;
+        for dep in entry.deps {
+            if let Some(registry) = dep.registry {
+                registries.insert((entry.vers.clone(), dep.name), registry);
+            }
+        }
+    }
+    registries
+}
+
+/// The line of a version in the index entry of a crate, as the registry served it during this run
+#[culpa::throws]
+#[fn_error_context::context("reading the index entry of {name} {version}")]
+pub(crate) fn line(url: &str, name: &str, version: &str) -> Option<String> {
+    let entries = ENTRIES.lock().unwrap();
+    let Some(body) = entries.get(&(url.to_owned(), name.to_lowercase())) else {
+        return None;
+    };
+    for line in body.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+        let entry: Entry = serde_json::from_slice(line)?;
+        if entry.vers == version {
+            return Some(String::from_utf8(line.to_owned())?);
+        }
+    }
+    None
+}
+
+/// The registries of dependencies of a crate, if its index entry was not fetched during this run
+/// they are assumed to all be from the same registry as the crate
+#[culpa::throws]
+#[fn_error_context::context("reading the registries of dependencies of {name}")]
+pub(crate) fn registries(url: &str, name: &str) -> Registries {
+    let entries = ENTRIES.lock().unwrap();
+    let Some(body) = entries.get(&(url.to_owned(), name.to_lowercase())) else {
+        tracing::warn!(
+            "the index entry of {name} was not fetched, assuming its dependencies are from the same registry"
+        );
+        return Registries::new();
+    };
+    parse(body)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{line, record, registries};
+
+    const BODY: &str = concat!(
+        r#"{"name":"foo","vers":"0.1.0","deps":[],"cksum":"0000000000000000000000000000000000000000000000000000000000000000","features":{},"yanked":false}"#,
+        "\n",
+        r#"{"name":"foo","vers":"0.2.0","deps":[{"name":"bar","req":"^1","features":[],"optional":false,"default_features":true,"target":null,"kind":"normal","registry":"sparse+https://other.example/"},{"name":"baz","req":"^1","features":[],"optional":false,"default_features":true,"target":null,"kind":"normal"}],"cksum":"0000000000000000000000000000000000000000000000000000000000000000","features":{},"yanked":false}"#,
+        "\n",
+    );
+
+    #[test]
+    fn recorded_registries() {
+        record("sparse+https://example.com/", "Foo", BODY.as_bytes());
+        let registries = registries("sparse+https://example.com/", "foo").unwrap();
+        assert_eq!(registries.len(), 1);
+        assert_eq!(
+            registries[&("0.2.0".to_owned(), "bar".to_owned())],
+            "sparse+https://other.example/"
+        );
+    }
+
+    #[test]
+    fn lines() {
+        record("sparse+https://lines.example/", "foo", BODY.as_bytes());
+        assert_eq!(
+            line("sparse+https://lines.example/", "foo", "0.1.0").unwrap(),
+            BODY.lines().next().map(str::to_owned)
+        );
+        assert_eq!(
+            line("sparse+https://lines.example/", "foo", "0.3.0").unwrap(),
+            None
+        );
+        assert_eq!(
+            line("sparse+https://unknown.example/", "foo", "0.1.0").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn unknown_registries() {
+        assert!(registries("sparse+https://unknown.example/", "foo")
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,49 @@
+use anyhow::Error;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex, OnceLock,
+};
+
+/// The exit code used when interrupted, the conventional 128 + SIGINT
+pub(crate) const EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static BARS: Mutex<Vec<indicatif::WeakProgressBar>> = Mutex::new(Vec::new());
+static CANCELLED_STYLE: OnceLock<indicatif::ProgressStyle> = OnceLock::new();
+
+/// Installs a Ctrl-C handler that removes any partially written outputs and marks unfinished
+/// progress bars as cancelled before exiting
+#[culpa::throws]
+pub(crate) fn install(cancelled: indicatif::ProgressStyle) {
+    CANCELLED_STYLE.get_or_init(|| cancelled);
+    ctrlc::set_handler(|| {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        let _temporaries = crate::atomic::remove_all();
+        let bars = BARS.lock().unwrap_or_else(|e| e.into_inner());
+        for bar in bars.iter().filter_map(|bar| bar.upgrade()) {
+            if !bar.is_finished() {
+                if let Some(style) = CANCELLED_STYLE.get() {
+                    bar.set_style(style.clone());
+                }
+                bar.abandon_with_message("cancelled");
+            }
+        }
+        std::process::exit(EXIT_CODE);
+    })?;
+}
+
+/// Registers a progress bar to be marked as cancelled if interrupted
+pub(crate) fn track(bar: indicatif::ProgressBar) -> indicatif::ProgressBar {
+    BARS.lock().unwrap().push(bar.downgrade());
+    bar
+}
+
+/// Blocks the current thread forever once interrupted, so that no more output is written while
+/// the handler is cleaning up
+pub(crate) fn checkpoint() {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        loop {
+            std::thread::park();
+        }
+    }
+}
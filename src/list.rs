@@ -0,0 +1,281 @@
+use anyhow::Error;
+use std::fmt::Write;
+
+/// How the entries of a crate are listed
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub(crate) enum Format {
+    /// Only the path of each entry
+    Plain,
+    /// The type, permissions, size and path of each entry, like `ls -l`, and the total size
+    Long,
+    /// A JSON object per crate, with its entries and total size
+    Json,
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Format::Plain => "plain",
+            Format::Long => "long",
+            Format::Json => "json",
+        })
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Listing<'a> {
+    name: &'a str,
+    version: &'a str,
+    entries: Vec<Entry>,
+    total_size: u64,
+}
+
+#[derive(serde::Serialize)]
+struct Entry {
+    path: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(serialize_with = "octal")]
+    mode: u32,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<String>,
+}
+
+/// Serializes permission bits as a string like `"0644"`
+fn octal<S: serde::Serializer>(mode: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{mode:04o}"))
+}
+
+fn kind(entry_type: tar::EntryType) -> &'static str {
+    match entry_type {
+        tar::EntryType::Regular => "file",
+        tar::EntryType::Directory => "dir",
+        tar::EntryType::Symlink => "symlink",
+        tar::EntryType::Link => "hardlink",
+        tar::EntryType::Fifo => "fifo",
+        tar::EntryType::Char => "char",
+        tar::EntryType::Block => "block",
+        _ => "other",
+    }
+}
+
+/// The `ls -l` style type and permissions of an entry
+fn permissions(kind: &str, mode: u32) -> String {
+    let mut permissions = String::from(match kind {
+        "file" => '-',
+        "dir" => 'd',
+        "symlink" => 'l',
+        "hardlink" => 'h',
+        "fifo" => 'p',
+        "char" => 'c',
+        "block" => 'b',
+        _ => '?',
+    });
+    permissions.extend("rwxrwxrwx".chars().enumerate().map(|(i, c)| {
+        if mode & (0o400 >> i) != 0 {
+            c
+        } else {
+            '-'
+        }
+    }));
+    permissions
+}
+
+/// Lists every entry in the archive without extracting it
+#[culpa::throws]
+#[fn_error_context::context("listing the files of {name} {version}")]
+pub(crate) fn list(
+    name: &str,
+    version: &str,
+    mut archive: tar::Archive<impl std::io::Read>,
+    format: Format,
+    limits: crate::unpack::Limits,
+) -> (String, usize, u64) {
+    let mut entries = Vec::new();
+    for entry in limits.entries(&mut archive)? {
+        let entry = entry?;
+        let header = entry.header();
+        if header.entry_type() == tar::EntryType::XGlobalHeader {
+            continue;
+        }
+        entries.push(Entry {
+            path: entry.path()?.display().to_string(),
+            kind: kind(header.entry_type()),
+            mode: header.mode()? & 0o7777,
+            size: header.size()?,
+            link: entry.link_name()?.map(|link| link.display().to_string()),
+        });
+    }
+    let total_size = entries.iter().map(|entry| entry.size).sum();
+    let count = entries.len();
+
+    let mut output = String::new();
+    match format {
+        Format::Plain => {
+            for entry in &entries {
+                writeln!(output, "{}", entry.path)?;
+            }
+        }
+        Format::Long => {
+            let width = entries
+                .iter()
+                .map(|entry| entry.size.to_string().len())
+                .max()
+                .unwrap_or(0);
+            for entry in &entries {
+                write!(
+                    output,
+                    "{} {:>width$} {}",
+                    permissions(entry.kind, entry.mode),
+                    entry.size,
+                    entry.path
+                )?;
+                if let Some(link) = &entry.link {
+                    write!(output, " -> {link}")?;
+                }
+                writeln!(output)?;
+            }
+            writeln!(output, "total {total_size} bytes in {count} entries")?;
+        }
+        Format::Json => {
+            output = serde_json::to_string(&Listing {
+                name,
+                version,
+                entries,
+                total_size,
+            })?;
+            output.push('\n');
+        }
+    }
+    (output, count, total_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{list, permissions, Format};
+    use crate::unpack::{Limits, Violation};
+
+    const LIMITS: Limits = Limits {
+        entries: 100,
+        bytes: 1000,
+    };
+
+    fn archive() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, entry_type, mode, contents) in [
+            ("foo-0.1.0/src", tar::EntryType::Directory, 0o755, &b""[..]),
+            (
+                "foo-0.1.0/src/lib.rs",
+                tar::EntryType::Regular,
+                0o644,
+                b"pub fn f() {}\n",
+            ),
+            (
+                "foo-0.1.0/run.sh",
+                tar::EntryType::Regular,
+                0o755,
+                b"#!/bin/sh\n",
+            ),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(mode);
+            header.set_size(contents.len() as u64);
+            builder.append_data(&mut header, path, contents).unwrap();
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_mode(0o777);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "foo-0.1.0/alias.rs", "src/lib.rs")
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn run(format: Format) -> (String, usize, u64) {
+        list(
+            "foo",
+            "0.1.0",
+            tar::Archive::new(&archive()[..]),
+            format,
+            LIMITS,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(permissions("file", 0o644), "-rw-r--r--");
+        assert_eq!(permissions("dir", 0o755), "drwxr-xr-x");
+        assert_eq!(permissions("symlink", 0o777), "lrwxrwxrwx");
+        assert_eq!(permissions("other", 0o4750), "?rwxr-x---");
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(
+            run(Format::Plain),
+            (
+                "foo-0.1.0/src\nfoo-0.1.0/src/lib.rs\nfoo-0.1.0/run.sh\nfoo-0.1.0/alias.rs\n"
+                    .to_owned(),
+                4,
+                24
+            )
+        );
+    }
+
+    #[test]
+    fn long() {
+        assert_eq!(
+            run(Format::Long).0,
+            "drwxr-xr-x  0 foo-0.1.0/src\n\
+             -rw-r--r-- 14 foo-0.1.0/src/lib.rs\n\
+             -rwxr-xr-x 10 foo-0.1.0/run.sh\n\
+             lrwxrwxrwx  0 foo-0.1.0/alias.rs -> src/lib.rs\n\
+             total 24 bytes in 4 entries\n"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let listing: serde_json::Value = serde_json::from_str(&run(Format::Json).0).unwrap();
+        assert_eq!(listing["name"], "foo");
+        assert_eq!(listing["total_size"], 24);
+        assert_eq!(
+            listing["entries"][1],
+            serde_json::json!({
+                "path": "foo-0.1.0/src/lib.rs",
+                "type": "file",
+                "mode": "0644",
+                "size": 14,
+            })
+        );
+        assert_eq!(listing["entries"][3]["link"], "src/lib.rs");
+    }
+
+    #[test]
+    fn limits() {
+        let violation = |limits| {
+            list(
+                "foo",
+                "0.1.0",
+                tar::Archive::new(&archive()[..]),
+                Format::Plain,
+                limits,
+            )
+            .unwrap_err()
+            .downcast::<Violation>()
+            .unwrap()
+        };
+        assert!(matches!(
+            violation(Limits { entries: 3, ..LIMITS }),
+            Violation::TooManyEntries(path, 3) if path == "foo-0.1.0/alias.rs"
+        ));
+        assert!(matches!(
+            violation(Limits { bytes: 20, ..LIMITS }),
+            Violation::TooLarge(path, 20) if path == "foo-0.1.0/run.sh"
+        ));
+    }
+}
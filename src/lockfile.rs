@@ -0,0 +1,264 @@
+use crate::{
+    package_id_spec::{registry_url, GitReference, GitSource, PackageIdSpec, Source},
+    version::VersionReq,
+};
+use anyhow::{anyhow, Context, Error};
+use std::{collections::BTreeMap, path::Path};
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct Lockfile {
+    #[serde(default)]
+    pub(crate) package: Vec<Package>,
+    /// Lockfiles before version 2 store checksums here, keyed by package id
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct Package {
+    pub(crate) name: String,
+    pub(crate) version: String,
+    pub(crate) source: Option<String>,
+    checksum: Option<String>,
+}
+
+/// A package locked to an exact version in a registry
+#[derive(Debug)]
+pub(crate) struct Locked {
+    pub(crate) spec: PackageIdSpec,
+    pub(crate) checksum: Option<[u8; 32]>,
+    /// Whether the version was taken from a lockfile, rather than a requirement
+    pub(crate) from_lockfile: bool,
+}
+
+#[culpa::throws]
+fn parse_checksum(checksum: &str) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    hex::decode_to_slice(checksum, &mut bytes)
+        .with_context(|| format!("invalid checksum {checksum:?}"))?;
+    bytes
+}
+
+impl Lockfile {
+    #[culpa::throws]
+    #[fn_error_context::context("reading lockfile {}", path.display())]
+    pub(crate) fn load(path: &Path) -> Self {
+        toml::from_str(&std::fs::read_to_string(path)?)?
+    }
+
+    /// The checksum of the package, stored either inline or in the metadata table
+    #[culpa::throws]
+    fn checksum(&self, package: &Package) -> Option<[u8; 32]> {
+        let checksum = match &package.checksum {
+            Some(checksum) => Some(checksum),
+            None => self.metadata.get(&format!(
+                "checksum {} {} ({})",
+                package.name,
+                package.version,
+                package.source.as_deref().unwrap_or_default()
+            )),
+        };
+        checksum
+            .map(|checksum| parse_checksum(checksum))
+            .transpose()?
+    }
+
+    /// The package locked to its exact version and source, registry packages will also have
+    /// their checksum
+    #[culpa::throws]
+    #[fn_error_context::context("locking {} {}", package.name, package.version)]
+    pub(crate) fn lock(&self, package: &Package) -> Locked {
+        let source = package
+            .source
+            .as_deref()
+            .context("path packages cannot be downloaded")?;
+        if let Some(git) = source.strip_prefix("git+") {
+            let (url, commit) = git
+                .split_once('#')
+                .context("git source is missing the locked commit")?;
+            let url = url.split_once('?').map_or(url, |(url, _)| url);
+            Locked {
+                spec: PackageIdSpec {
+                    source: Some(Source::Git(GitSource {
+                        url: url.to_owned(),
+                        reference: Some(GitReference::Rev(commit.to_owned())),
+                    })),
+                    name: package.name.parse()?,
+                    version_req: Some(VersionReq::Exact(package.version.clone())),
+                },
+                checksum: None,
+                from_lockfile: true,
+            }
+        } else if source.starts_with("registry+") || source.starts_with("sparse+") {
+            let spec = format!("{source}#{}@={}", package.name, package.version);
+            Locked {
+                spec: spec.parse()?,
+                checksum: self.checksum(package)?,
+                from_lockfile: true,
+            }
+        } else {
+            culpa::throw!(anyhow!("unsupported source {source}"));
+        }
+    }
+}
+
+/// Reads all registry packages from the lockfile, logging the packages that had to be skipped
+#[culpa::throws]
+pub(crate) fn read(path: &Path) -> Vec<Locked> {
+    let lockfile = Lockfile::load(path)?;
+    let mut locked = Vec::new();
+    for package in &lockfile.package {
+        match package.source.as_deref() {
+            None => tracing::info!(
+                "skipping {} {}, path packages cannot be downloaded",
+                package.name,
+                package.version
+            ),
+            Some(source) if !source.starts_with("registry+") && !source.starts_with("sparse+") => {
+                tracing::info!(
+                    "skipping {} {} from {source}, only registry packages can be downloaded",
+                    package.name,
+                    package.version
+                )
+            }
+            Some(source)
+                if source
+                    .strip_prefix("registry+")
+                    .is_some_and(|url| !registry_url(url).starts_with("sparse+")) =>
+            {
+                tracing::warn!(
+                    "skipping {} {} from {source}, only sparse registries are supported",
+                    package.name,
+                    package.version
+                )
+            }
+            Some(_) => locked.push(lockfile.lock(package)?),
+        }
+    }
+    locked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, Lockfile};
+    use crate::package_id_spec::{GitReference, GitSource, Source};
+
+    const CHECKSUM: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+
+    fn lockfile(s: &str) -> Lockfile {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn inline_checksums() {
+        let lockfile = lockfile(&format!(
+            r#"
+            [[package]]
+            name = "foo"
+            version = "1.0.0"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+            checksum = "{CHECKSUM}"
+            "#
+        ));
+        let locked = lockfile.lock(&lockfile.package[0]).unwrap();
+        assert_eq!(locked.spec.source, Some(Source::crates_io()));
+        assert_eq!(
+            locked.spec.to_string(),
+            format!("{}#foo@=1.0.0", Source::crates_io())
+        );
+        assert_eq!(locked.checksum.unwrap()[31], 1);
+        assert!(locked.from_lockfile);
+    }
+
+    #[test]
+    fn metadata_checksums() {
+        let lockfile = lockfile(&format!(
+            r#"
+            [[package]]
+            name = "foo"
+            version = "1.0.0"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+
+            [metadata]
+            "checksum foo 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)" = "{CHECKSUM}"
+            "#
+        ));
+        let locked = lockfile.lock(&lockfile.package[0]).unwrap();
+        assert_eq!(locked.checksum.unwrap()[31], 1);
+    }
+
+    #[test]
+    fn invalid_checksum() {
+        let lockfile = lockfile(
+            r#"
+            [[package]]
+            name = "foo"
+            version = "1.0.0"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+            checksum = "abc"
+            "#,
+        );
+        assert!(lockfile.lock(&lockfile.package[0]).is_err());
+    }
+
+    #[test]
+    fn git_packages() {
+        let lockfile = lockfile(
+            r#"
+            [[package]]
+            name = "foo"
+            version = "0.1.0"
+            source = "git+https://example.com/foo.git?branch=main#0123abcd"
+            "#,
+        );
+        let locked = lockfile.lock(&lockfile.package[0]).unwrap();
+        assert_eq!(
+            locked.spec.source,
+            Some(Source::Git(GitSource {
+                url: "https://example.com/foo.git".to_owned(),
+                reference: Some(GitReference::Rev("0123abcd".to_owned())),
+            }))
+        );
+        assert_eq!(locked.checksum, None);
+    }
+
+    #[test]
+    fn read_skips_unsupported_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Cargo.lock");
+        std::fs::write(
+            &path,
+            r#"
+            [[package]]
+            name = "app"
+            version = "0.1.0"
+
+            [[package]]
+            name = "foo"
+            version = "1.0.0"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+
+            [[package]]
+            name = "bar"
+            version = "1.0.0"
+            source = "sparse+https://example.com/index/"
+
+            [[package]]
+            name = "baz"
+            version = "1.0.0"
+            source = "registry+https://example.com/git-index"
+
+            [[package]]
+            name = "qux"
+            version = "0.1.0"
+            source = "git+https://example.com/qux.git#0123abcd"
+            "#,
+        )
+        .unwrap();
+        let locked = read(&path).unwrap();
+        assert_eq!(
+            Vec::from_iter(locked.iter().map(|locked| locked.spec.name.0.as_str())),
+            ["foo", "bar"]
+        );
+    }
+}
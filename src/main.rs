@@ -1,12 +1,36 @@
+mod atomic;
 mod cache;
+mod cfg;
 mod crate_name;
+mod deps;
+mod git;
+mod index_cache;
+mod interrupt;
+mod list;
+mod lockfile;
+mod manifest;
 mod package_id_spec;
+mod playground;
+mod portability;
+mod registry;
+mod spec_file;
 mod unpack;
+mod vendor;
+mod version;
+mod workspace;
 
-use crate::{crate_name::CrateName, package_id_spec::PackageIdSpec};
+use crate::{
+    crate_name::CrateName,
+    package_id_spec::{GitReference, GitSource, PackageIdSpec, Source},
+    version::{Version, VersionReq},
+};
 use anyhow::{anyhow, Context, Error};
 use clap::{CommandFactory, FromArgMatches, Parser};
-use std::{io::Read, time::Duration};
+use std::{
+    io::{BufRead, Read},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tracing_subscriber::EnvFilter;
 use ureq::OrAnyStatus;
 
@@ -22,11 +46,14 @@ const CRATE_SIZE_LIMIT: u64 = 40 * 1024 * 1024;
     propagate_version = true
 )]
 enum Command {
-    #[command(about)]
+    #[command(
+        about,
+        after_long_help = "Outputs are written to temporary siblings and only moved into place once complete. If interrupted these are removed, and cargo-dl exits with code 130."
+    )]
     Dl(App),
 }
 
-#[derive(Debug, Parser)]
+#[derive(Clone, Debug, Parser)]
 struct App {
     /// Specify this flag to have the crate extracted automatically.
     ///
@@ -41,19 +68,277 @@ struct App {
     #[arg(short, long)]
     output: Option<String>,
 
+    /// Replace any existing output completely, instead of failing.
+    #[arg(long, conflicts_with = "merge")]
+    force: bool,
+
+    /// Extract into an existing output directory, overwriting files that are also in the crate and
+    /// leaving other files in place.
+    #[arg(long, requires = "extract")]
+    merge: bool,
+
+    /// Print the files at this path in the crate to stdout instead of writing the crate anywhere,
+    /// relative to the crate root and optionally a glob. Can be given multiple times.
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_parser = unpack::parse_glob,
+        conflicts_with_all = ["extract", "output", "force", "vendor", "update_vendor", "local_registry", "sparse_mirror", "playground"],
+    )]
+    cat: Vec<globset::Glob>,
+
+    /// List every entry in the crate with its type, permissions and size instead of writing the
+    /// crate anywhere.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "plain",
+        conflicts_with_all = ["cat", "extract", "output", "force", "vendor", "update_vendor", "local_registry", "sparse_mirror", "playground"],
+    )]
+    list_files: Option<list::Format>,
+
+    /// Print a diff of the changes cargo made to the author's original manifest when normalising
+    /// it for publishing, instead of writing the crate anywhere.
+    #[arg(
+        long,
+        conflicts_with_all = ["cat", "list_files", "extract", "output", "force", "vendor", "update_vendor", "local_registry", "sparse_mirror", "playground"],
+    )]
+    manifest_diff: bool,
+
+    /// Extract the author's original manifest as `Cargo.toml` instead of the one cargo normalised
+    /// when publishing, which is kept as `Cargo.toml.normalized`.
+    #[arg(long, requires = "extract")]
+    original_manifest: bool,
+
+    /// Strip this many leading components from paths in the archive when extracting, instead of
+    /// requiring every entry to be inside the `<name>-<version>/` directory.
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "extract",
+        conflicts_with = "keep_top_level"
+    )]
+    strip_components: Option<usize>,
+
+    /// Only extract files matching this glob, relative to the crate root (e.g. `src/**`). Can be
+    /// given multiple times, and fails if any pattern matches nothing.
+    #[arg(long, value_name = "GLOB", value_parser = unpack::parse_glob, requires = "extract")]
+    include: Vec<globset::Glob>,
+
+    /// Skip files matching this glob, relative to the crate root (e.g. `tests/**`). Can be given
+    /// multiple times.
+    #[arg(long, value_name = "GLOB", value_parser = unpack::parse_glob, requires = "extract")]
+    exclude: Vec<globset::Glob>,
+
+    /// Keep the archive's top-level directory when extracting, so that the output contains
+    /// `<name>-<version>/` (or whatever the archive has).
+    #[arg(long, requires = "extract")]
+    keep_top_level: bool,
+
+    /// The most entries a crate may contain when extracting or reading it.
+    #[arg(long, value_name = "COUNT", default_value_t = 100_000)]
+    max_entries: u64,
+
+    /// The most bytes a crate may unpack to when extracting or reading it.
+    #[arg(long, value_name = "BYTES", default_value_t = 512 * 1024 * 1024)]
+    max_unpacked_size: u64,
+
+    /// Give extracted files these permissions instead of those in the archive, as octal modes for
+    /// files and for directories and executable files, e.g. `0644/0755`. If only one mode is given
+    /// executables are also executable by whoever can read them.
+    #[arg(long, value_name = "FILE[/EXECUTABLE]", requires = "extract")]
+    mode: Option<unpack::Mode>,
+
+    /// Make the whole extracted tree read-only, for browsing without accidentally modifying it.
+    #[arg(long, requires = "extract")]
+    read_only: bool,
+
+    /// Which modification time extracted files are given.
+    #[arg(long, value_enum, default_value_t = unpack::Mtime::Archive, requires = "extract")]
+    mtime: unpack::Mtime,
+
+    /// Fail when extracting a crate with paths that would not work on case-insensitive or Windows
+    /// filesystems, such as names differing only by case or reserved by Windows, instead of only
+    /// warning about them.
+    #[arg(long)]
+    deny_unportable: bool,
+
+    /// Extract the crate(s) into a directory usable as a vendored source, like `cargo vendor`.
+    ///
+    /// Each crate is extracted to `<DIR>/<name>`, or `<DIR>/<name>-<version>` for all but the
+    /// newest version when multiple versions of a crate are downloaded, along with the
+    /// `.cargo-checksum.json` cargo requires. The source replacement config needed to use it is
+    /// printed once done.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["output", "extract"])]
+    vendor: Option<PathBuf>,
+
+    /// Add or upgrade the crate(s) in an existing vendor directory.
+    ///
+    /// A vendored copy of the same or a semver compatible version is replaced, otherwise the crate
+    /// is added alongside any other versions. A replaced copy in a versioned directory such as
+    /// `foo-1.0.0` moves to `foo` if that is free, otherwise it is renamed for the new version.
+    /// Existing copies whose files no longer match their `.cargo-checksum.json` are never
+    /// modified.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["output", "extract", "vendor"])]
+    update_vendor: Option<PathBuf>,
+
+    /// Add the crate(s) to a local registry, for use with `local-registry` source replacement.
+    ///
+    /// The `.crate` files are written to `<DIR>` and their index entries, copied from the
+    /// registry's index, to `<DIR>/index`. An existing local registry is added to.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["output", "extract", "vendor", "update_vendor"])]
+    local_registry: Option<PathBuf>,
+
+    /// Export the crate(s) as a static sparse index mirror, to be served from --mirror-url.
+    ///
+    /// Writes `config.json`, the index entries copied from the registry's index, and the `.crate`
+    /// files at the paths their download URLs resolve to within the mirror. An existing mirror is
+    /// added to.
+    #[arg(long, value_name = "DIR", requires = "mirror_url", conflicts_with_all = ["output", "extract", "vendor", "update_vendor", "local_registry"])]
+    sparse_mirror: Option<PathBuf>,
+
+    /// The URL the sparse mirror will be served from.
+    #[arg(long, value_name = "URL", requires = "sparse_mirror")]
+    mirror_url: Option<String>,
+
+    /// The `dl` download URL template written to the sparse mirror's config.json.
+    ///
+    /// Defaults to serving the crates from the mirror itself. If the crates are downloaded from
+    /// elsewhere, only the index is written.
+    #[arg(long, value_name = "URL", requires = "sparse_mirror")]
+    mirror_dl: Option<String>,
+
+    /// The `api` URL written to the sparse mirror's config.json.
+    #[arg(long, value_name = "URL", requires = "sparse_mirror")]
+    mirror_api: Option<String>,
+
+    /// Create a scratch binary project depending on the crate, for quickly trying out its API.
+    ///
+    /// The crate is extracted to a cache in the user's cache directory and the project is created
+    /// next to it, or at the path given by --output. The location of the project is printed.
+    #[arg(long, conflicts_with_all = ["extract", "vendor", "update_vendor", "local_registry", "sparse_mirror"])]
+    playground: bool,
+
+    /// Copy the crate's examples into the playground as binaries.
+    #[arg(long, requires = "playground")]
+    playground_examples: bool,
+
     // TODO: Easy way to download latest pre-release
     /// The crate(s) to download.
     ///
     /// Optionally including which version of the crate to download after `@`, in the standard
     /// semver constraint format used in Cargo.toml. If unspecified the newest non-prerelease,
     /// non-yanked version will be fetched.
-    #[arg(name = "CRATE[@VERSION_REQ]", required = true)]
+    ///
+    /// Fully qualified package id specs as output by `cargo pkgid` are also accepted, e.g.
+    /// `sparse+https://index.example/#foo@1.2.3`, in which case the crate is fetched from that
+    /// registry and the version must match exactly.
+    #[arg(name = "CRATE[@VERSION_REQ]", required_unless_present_any = ["lockfile", "from_file"])]
     specs: Vec<PackageIdSpec>,
 
+    /// Read additional crate specs from a file, or stdin if `-`.
+    ///
+    /// One spec per line, in the same formats accepted as arguments. Blank lines and comments
+    /// starting with `#` are ignored, and specs repeated in the file or the arguments are only
+    /// downloaded once.
+    #[arg(long, value_name = "PATH")]
+    from_file: Option<PathBuf>,
+
+    /// Download every registry package locked in this Cargo.lock.
+    ///
+    /// Each package is downloaded at its exact locked version and verified against both the
+    /// lockfile checksum and the index. Git and path packages are skipped.
+    #[arg(long, value_name = "PATH")]
+    lockfile: Option<PathBuf>,
+
+    /// Use the version of each crate that the current workspace depends on.
+    ///
+    /// The enclosing Cargo.lock is used to find the exact locked version, falling back to the
+    /// version requirement in the enclosing Cargo.toml. If a crate is locked at multiple versions
+    /// then all of them are downloaded, unless narrowed down by a version requirement on the
+    /// crate. Path dependencies, and git dependencies that are not locked, are skipped.
+    #[arg(long)]
+    from_workspace: bool,
+
+    /// After extracting a crate, also vendor the dependencies locked in its own Cargo.lock.
+    ///
+    /// The dependencies are vendored into a `vendor` directory inside the extracted crate, and a
+    /// `.cargo/config.toml` is written alongside so that `cargo build --offline --locked` works
+    /// there.
+    #[arg(long, requires = "extract")]
+    with_locked_deps: bool,
+
+    /// Also download every dependency of the crate(s), resolved from the index.
+    ///
+    /// Dependencies are followed according to their kind, whether they are optional and which
+    /// target they apply to, choosing the newest compatible version of each.
+    #[arg(long)]
+    deps: bool,
+
+    /// Include dev-dependencies of the requested crate(s) when using --deps.
+    #[arg(long, requires = "deps")]
+    dev_deps: bool,
+
+    /// Features to activate on the requested crate(s) when using --deps.
+    #[arg(long, requires = "deps", value_delimiter = ',')]
+    features: Vec<String>,
+
+    /// Activate all features of the requested crate(s) when using --deps.
+    #[arg(long, requires = "deps")]
+    all_features: bool,
+
+    /// Do not activate the `default` feature of the requested crate(s) when using --deps.
+    #[arg(long, requires = "deps")]
+    no_default_features: bool,
+
+    /// Target triple to follow platform specific dependencies for when using --deps.
+    ///
+    /// Defaults to the host, use `all` to follow dependencies for every platform.
+    #[arg(long, requires = "deps", value_name = "TRIPLE")]
+    target: Option<String>,
+
+    /// Fetch the crate(s) from a git repository rather than a registry.
+    ///
+    /// The crate is located within the repository's workspace and packaged the same as `cargo
+    /// package` would. Fully qualified specs such as `git+https://example.com/repo.git?rev=abc#foo`
+    /// can also be used to specify the repository per crate.
+    #[arg(long, value_name = "URL")]
+    git: Option<String>,
+
+    /// Git revision to check out when using --git.
+    #[arg(long, requires = "git", conflicts_with_all = ["branch", "tag"])]
+    rev: Option<String>,
+
+    /// Git branch to check out when using --git.
+    #[arg(long, requires = "git", conflicts_with = "tag")]
+    branch: Option<String>,
+
+    /// Git tag to check out when using --git.
+    #[arg(long, requires = "git")]
+    tag: Option<String>,
+
     /// Allow yanked versions to be chosen.
     #[arg(long)]
     allow_yanked: bool,
 
+    /// Only select versions that have this feature, can be specified multiple times.
+    ///
+    /// Features implicitly defined by optional dependencies are also taken into account.
+    #[arg(long, value_name = "FEATURE")]
+    with_feature: Vec<String>,
+
+    /// Print every candidate version along with why it was accepted or rejected.
+    #[arg(long)]
+    explain: bool,
+
+    /// Allow selecting versions that are not valid semver.
+    ///
+    /// Some old crates and private registries have such versions, they can only be selected by
+    /// their exact version string, e.g. `foo@=0.1`.
+    #[arg(long)]
+    lenient_versions: bool,
+
     /// Disable checking cargo cache for the crate file.
     #[arg(long = "no-cache", action(clap::ArgAction::SetFalse))]
     cache: bool,
@@ -92,6 +377,24 @@ fn do_index_request(app: &App, request: http::request::Builder) -> http::Respons
 #[derive(thiserror::Error, Copy, Clone, Debug, displaydoc::Display)]
 struct LoggedError;
 
+/// Why a version was not selected
+#[derive(Clone, Debug, displaydoc::Display)]
+enum Rejection {
+    /// not valid semver, use `--lenient-versions` to allow it
+    NonSemver,
+    /// yanked, use `--allow-yanked` to allow it
+    Yanked,
+    /// pre-release not explicitly requested by `{0}`
+    Prerelease(VersionReq),
+    /// does not match `{0}`
+    Requirement(VersionReq),
+    /// missing feature {0}
+    MissingFeatures(String),
+}
+
+/// A version of the crate, along with all the reasons it was rejected
+type Candidate<'a> = (Version, &'a crates_index::Version, Vec<Rejection>);
+
 #[culpa::throws]
 fn find_crate_in_index(
     app: &App,
@@ -108,7 +411,20 @@ fn find_crate_in_index(
             name
         ));
         if app.update_index {
-            let response = do_index_request(app, index.make_cache_request(&name)?)?;
+            let mut request = index.make_cache_request(&name)?;
+            if app.deps || app.local_registry.is_some() || app.sparse_mirror.is_some() {
+                // Fields `crates_index` does not know about, like the registries of
+                // dependencies, are only in the entry as the registry serves it, so it must not
+                // be answered with not modified
+                if let Some(headers) = request.headers_mut() {
+                    headers.remove(http::header::IF_NONE_MATCH);
+                    headers.remove(http::header::IF_MODIFIED_SINCE);
+                }
+            }
+            let response = do_index_request(app, request)?;
+            if response.status() == http::StatusCode::OK {
+                index_cache::record(&format!("sparse+{}", index.url()), &name, response.body());
+            }
             if let Some(krate) = index.parse_cache_response(&name, response, true)? {
                 return Some((name, krate));
             }
@@ -125,6 +441,71 @@ fn find_crate_in_index(
     None
 }
 
+/// Lists every candidate version, newest first, along with why it was selected, accepted or
+/// rejected
+#[culpa::throws(std::fmt::Error)]
+fn explain(
+    name: &str,
+    version_request: &VersionReq,
+    candidates: &[Candidate<'_>],
+    selected: Option<usize>,
+) -> String {
+    use std::fmt::Write;
+    let accepted = candidates
+        .iter()
+        .filter(|(_, _, rejections)| rejections.is_empty())
+        .count();
+    let mut explanation = format!(
+        "{name}: selecting from {} versions with requirement `{version_request}`\n",
+        candidates.len(),
+    );
+    for (i, (num, _, rejections)) in candidates.iter().enumerate() {
+        if rejections.is_empty() {
+            if Some(i) == selected {
+                writeln!(
+                    explanation,
+                    "  {num:>16}  selected, the newest of {accepted} accepted versions"
+                )?;
+            } else {
+                writeln!(
+                    explanation,
+                    "  {num:>16}  accepted, but older than the selected version"
+                )?;
+            }
+        } else {
+            let reasons = Vec::from_iter(rejections.iter().map(|rejection| rejection.to_string()));
+            writeln!(explanation, "  {num:>16}  rejected, {}", reasons.join("; "))?;
+        }
+    }
+    explanation
+}
+
+/// Whether the version has the feature, either explicitly or implicitly through an optional
+/// dependency that is not referenced with `dep:` syntax
+fn has_feature(version: &crates_index::Version, feature: &str) -> bool {
+    let features = version.features();
+    features.contains_key(feature)
+        || version
+            .dependencies()
+            .iter()
+            .any(|dep| dep.is_optional() && dep.name() == feature)
+            && !features
+                .values()
+                .flatten()
+                .any(|value| value.strip_prefix("dep:") == Some(feature))
+}
+
+#[culpa::throws]
+#[fn_error_context::context("opening index {}", url)]
+fn open_index(url: &str) -> crates_index::SparseIndex {
+    if !url.starts_with("sparse+") {
+        culpa::throw!(anyhow!(
+            "only sparse registries are supported, use the `sparse+` url of the registry"
+        ));
+    }
+    crates_index::SparseIndex::from_url(url)?
+}
+
 #[culpa::throws]
 #[fn_error_context::context("getting index config for {}", index.url())]
 fn config(app: &App, index: &crates_index::SparseIndex) -> crates_index::IndexConfig {
@@ -138,6 +519,121 @@ fn config(app: &App, index: &crates_index::SparseIndex) -> crates_index::IndexCo
     }
 }
 
+/// A crate to acquire
+struct Job {
+    spec: PackageIdSpec,
+    /// The checksum the crate was locked to, if any
+    checksum: Option<[u8; 32]>,
+    /// Whether the spec is an exact version from a lockfile, which is used even once yanked, the
+    /// same as cargo does
+    locked: bool,
+    /// The version already selected from the index, if any
+    selected: Option<crates_index::Version>,
+}
+
+/// A crate that has been acquired
+struct Delivered {
+    name: String,
+    version: String,
+    source: Source,
+    /// The index entry of registry crates
+    entry: Option<crates_index::Version>,
+    /// The extracted crate, when it is only moved into place once its locked dependencies are
+    /// vendored into it
+    extracted: Option<atomic::Partial>,
+}
+
+impl Delivered {
+    /// The registry and index entry of registry crates
+    fn registry_entry(&self) -> Option<(&str, &crates_index::Version)> {
+        match (&self.source, &self.entry) {
+            (Source::Registry(registry), Some(entry)) => Some((registry, entry)),
+            _ => None,
+        }
+    }
+}
+
+/// Renames the vendored crates to their final directories, returning the config needed to use the
+/// vendor directory from `location`
+#[culpa::throws]
+fn finish_vendor(dir: &Path, location: &str, delivered: &[Delivered], force: bool) -> String {
+    vendor::finish(
+        dir,
+        &Vec::from_iter(
+            delivered
+                .iter()
+                .map(|krate| (krate.name.clone(), krate.version.clone())),
+        ),
+        force,
+    )?;
+    let sources =
+        std::collections::BTreeSet::from_iter(delivered.iter().map(|krate| krate.source.clone()));
+    vendor::config("vendored-sources", "directory", location, &sources)?
+}
+
+/// The progress bar styles shared between all crates being acquired
+struct Styles {
+    spinner: indicatif::ProgressStyle,
+    success: indicatif::ProgressStyle,
+    failure: indicatif::ProgressStyle,
+    download: indicatif::ProgressStyle,
+}
+
+impl Styles {
+    #[culpa::throws]
+    fn new() -> Self {
+        Self {
+            spinner: indicatif::ProgressStyle::default_bar()
+                .template("{prefix:>40.cyan} {spinner} {msg}")?,
+            success: indicatif::ProgressStyle::default_bar()
+                .template("{prefix:>40.green} {spinner} {msg}")?,
+            failure: indicatif::ProgressStyle::default_bar()
+                .template("{prefix:>40.red} {spinner} {msg}")?,
+            download: indicatif::ProgressStyle::default_bar().template("{prefix:>40.cyan} {spinner} {msg}
+                                   [{bar:27}] {bytes:>9}/{total_bytes:9}  {bytes_per_sec} {elapsed:>4}/{eta:4}")?,
+        }
+    }
+}
+
+/// A crate archive that has been verified against its expected checksum
+enum CrateFile {
+    /// Found in the cargo cache
+    Cached(PathBuf),
+    /// Held in memory after downloading or packaging
+    Data(Vec<u8>),
+}
+
+impl CrateFile {
+    #[culpa::throws]
+    fn len(&self) -> u64 {
+        match self {
+            CrateFile::Cached(path) => std::fs::metadata(path)?.len(),
+            CrateFile::Data(data) => u64::try_from(data.len())?,
+        }
+    }
+
+    #[culpa::throws]
+    fn reader(&self) -> Box<dyn BufRead + '_> {
+        let reader: Box<dyn BufRead> = match self {
+            CrateFile::Cached(path) => {
+                Box::new(std::io::BufReader::new(std::fs::File::open(path)?))
+            }
+            CrateFile::Data(data) => Box::new(std::io::Cursor::new(data)),
+        };
+        reader
+    }
+
+    #[culpa::throws]
+    fn write_to(&self, output: impl AsRef<Path>) {
+        match self {
+            CrateFile::Cached(path) => {
+                std::fs::copy(path, output)?;
+            }
+            CrateFile::Data(data) => std::fs::write(output, data)?,
+        }
+    }
+}
+
 impl App {
     fn slow(&self) {
         if self.slooooow {
@@ -145,229 +641,957 @@ impl App {
         }
     }
 
+    /// The source a spec should be fetched from, taking `--git` into account
+    fn source(&self, spec: &PackageIdSpec) -> Source {
+        if let Some(source) = &spec.source {
+            return source.clone();
+        }
+        if let Some(url) = &self.git {
+            let reference = if let Some(rev) = &self.rev {
+                Some(GitReference::Rev(rev.clone()))
+            } else if let Some(branch) = &self.branch {
+                Some(GitReference::Branch(branch.clone()))
+            } else {
+                self.tag.clone().map(GitReference::Tag)
+            };
+            return Source::Git(GitSource {
+                url: url.clone(),
+                reference,
+            });
+        }
+        Source::crates_io()
+    }
+
+    /// Finds the newest version of the crate in the registry matching the spec
     #[culpa::throws]
-    #[tracing::instrument(fields(%self))]
-    fn run(&'static self) {
-        if self.specs.len() > 1 && self.output.is_some() {
-            culpa::throw!(anyhow!("cannot use --output with multiple crates"));
+    fn select_version(
+        &self,
+        spec: &PackageIdSpec,
+        locked: bool,
+        registry: &str,
+        bar: &indicatif::ProgressBar,
+        styles: &Styles,
+    ) -> crates_index::Version {
+        let index = open_index(registry)?;
+        let Some((name, krate)) = find_crate_in_index(self, &index, &spec.name.0, bar)? else {
+            bar.set_style(styles.failure.clone());
+            bar.finish_with_message("could not find crate in the index");
+            culpa::throw!(LoggedError);
+        };
+        if name != spec.name.0 {
+            tracing::warn!("Corrected name from {} to {name}", spec.name.0);
         }
 
-        let spinner_style = Box::leak(Box::new(
-            indicatif::ProgressStyle::default_bar()
-                .template("{prefix:>40.cyan} {spinner} {msg}")?,
-        ));
-        let success_style = Box::leak(Box::new(
-            indicatif::ProgressStyle::default_bar()
-                .template("{prefix:>40.green} {spinner} {msg}")?,
-        ));
-        let failure_style = Box::leak(Box::new(
-            indicatif::ProgressStyle::default_bar().template("{prefix:>40.red} {spinner} {msg}")?,
-        ));
-        let download_style = Box::leak(Box::new(indicatif::ProgressStyle::default_bar().template("{prefix:>40.cyan} {spinner} {msg}
-                                   [{bar:27}] {bytes:>9}/{total_bytes:9}  {bytes_per_sec} {elapsed:>4}/{eta:4}")?));
+        bar.set_message("selecting version");
+        bar.enable_steady_tick(Duration::from_millis(100));
+        self.slow();
 
-        let bars: &indicatif::MultiProgress = Box::leak(Box::new(indicatif::MultiProgress::new()));
-        let thread = std::thread::spawn(move || {
-            let config = {
-                let bar = bars
-                    .add(indicatif::ProgressBar::new_spinner())
-                    .with_style(spinner_style.clone())
-                    .with_prefix("index")
-                    .with_message(stylish::ansi::format!(
-                        "downloading {:(fg=magenta)}",
-                        "config.json"
-                    ));
-                bar.enable_steady_tick(Duration::from_millis(100));
-                let index = crates_index::SparseIndex::new_cargo_default()?;
-                Box::leak(Box::new(config(self, &index)?))
-            };
+        let version_request = spec.version_req.clone().unwrap_or(VersionReq::STAR);
+        let mut candidates = Vec::from_iter(krate.versions().iter().map(|version| {
+            let num = Version::parse(version.version());
+            let rejections = self.rejections(&num, version, &version_request, locked);
+            (num, version, rejections)
+        }));
+        candidates.sort_by(|(a, _, _), (b, _, _)| a.cmp(b).reverse());
+        if !self.lenient_versions {
+            for (num, _, _) in candidates.iter().filter(|(num, _, _)| !num.is_semver()) {
+                tracing::warn!("Ignoring non-semver version {num}, use `--lenient-versions` to allow selecting it");
+            }
+        }
 
-            let threads = Vec::from_iter(self.specs.iter().map(|spec| {
-                let bar = bars.add(indicatif::ProgressBar::new_spinner()).with_style(spinner_style.clone());
-                (spec, std::thread::spawn(|| {
-                    let bar = bar;
-                    bar.tick();
-                    bar.set_prefix(spec.to_string());
-                    bar.set_style(spinner_style.clone());
-                    bar.enable_steady_tick(Duration::from_millis(100));
-                    let index = crates_index::SparseIndex::new_cargo_default()?;
-                    let Some((name, krate)) = find_crate_in_index(self, &index, &spec.name.0, &bar)? else {
-                        bar.set_style(failure_style.clone());
-                        bar.finish_with_message("could not find crate in the index");
-                        return Err(LoggedError.into());
-                    };
-                    if name != spec.name.0 {
-                        tracing::warn!("Corrected name from {} to {name}", spec.name.0);
-                    }
+        let selected = candidates
+            .iter()
+            .position(|(_, _, rejections)| rejections.is_empty());
 
-                    bar.set_message("selecting version");
-                    bar.enable_steady_tick(Duration::from_millis(100));
-                    self.slow();
+        if self.explain {
+            let explanation = explain(krate.name(), &version_request, &candidates, selected)?;
+            bar.suspend(|| print!("{explanation}"));
+        }
 
-                    tracing::debug!(
-                        "all available versions: {:?}",
-                        Vec::from_iter(krate.versions().iter().map(|v| v.version()))
-                    );
+        match selected {
+            Some(i) => candidates[i].1.clone(),
+            None => {
+                bar.set_style(styles.failure.clone());
+                bar.finish_with_message(self.no_match(spec, &candidates)?);
+                culpa::throw!(LoggedError);
+            }
+        }
+    }
 
-                    let version_request = spec.version_req.clone().unwrap_or(semver::VersionReq::STAR);
-                    let versions = {
-                        let mut versions: Vec<_> = krate
-                            .versions()
-                            .iter()
-                            .filter(|version| self.allow_yanked || !version.is_yanked())
-                            .filter_map(|version| match semver::Version::parse(version.version()) {
-                                Ok(num) => Some((num, version)),
-                                Err(err) => {
-                                    tracing::warn!(
-                                        "Ignoring non-semver version {} {err:#?}",
-                                        version.version()
-                                    );
-                                    None
-                                }
-                            })
-                            .filter(|(num, _)| version_request.matches(num))
-                            .collect();
-                        versions.sort_by(|(a, _), (b, _)| a.cmp(b).reverse());
-                        versions
-                    };
+    /// Explains why no version could be selected, pointing out what would allow the newest
+    /// candidate that was rejected for a single kind of reason
+    #[culpa::throws(std::fmt::Error)]
+    fn no_match(&self, spec: &PackageIdSpec, candidates: &[Candidate<'_>]) -> String {
+        use std::fmt::Write;
+
+        let rejected_only_by = |f: fn(&Rejection) -> bool| {
+            candidates
+                .iter()
+                .find(|(_, _, rejections)| !rejections.is_empty() && rejections.iter().all(f))
+        };
+
+        let mut msg = "no matching version found".to_owned();
+        if let Some((num, version, rejections)) =
+            rejected_only_by(|r| matches!(r, Rejection::MissingFeatures(_)))
+        {
+            let missing = Vec::from_iter(rejections.iter().map(|rejection| rejection.to_string()));
+            write!(
+                msg,
+                "; the newest matching version {} {} is {}",
+                version.name(),
+                num,
+                missing.join(", ")
+            )?;
+        } else if let Some((num, version, _)) =
+            rejected_only_by(|r| matches!(r, Rejection::NonSemver))
+        {
+            write!(msg, "; only the non-semver version {} {} matched, use `--lenient-versions` to download it", version.name(), num)?;
+        } else if let Some((num, version, _)) = rejected_only_by(|r| matches!(r, Rejection::Yanked))
+        {
+            write!(
+                msg,
+                "; the yanked version {} {} matched, use `--allow-yanked` to download it",
+                version.name(),
+                num
+            )?;
+        } else if let Some((num, _, _)) = candidates.first().filter(|_| {
+            !self.lenient_versions && candidates.iter().all(|(num, _, _)| !num.is_semver())
+        }) {
+            write!(msg, "; all versions are non-semver, use `--lenient-versions` and an exact version like `{}@={}` to select one", spec.name, num)?;
+        }
+        msg
+    }
 
+    /// All the reasons the version cannot be selected, if none then it is accepted
+    fn rejections(
+        &self,
+        num: &Version,
+        version: &crates_index::Version,
+        version_request: &VersionReq,
+        locked: bool,
+    ) -> Vec<Rejection> {
+        let mut rejections = Vec::new();
+        if !self.lenient_versions && !num.is_semver() {
+            rejections.push(Rejection::NonSemver);
+        }
+        if !self.allow_yanked && !locked && version.is_yanked() {
+            rejections.push(Rejection::Yanked);
+        }
+        if !version_request.matches(num) {
+            match num {
+                Version::Semver(v)
+                    if !v.pre.is_empty()
+                        && version_request.matches(&Version::Semver(semver::Version::new(
+                            v.major, v.minor, v.patch,
+                        ))) =>
+                {
+                    rejections.push(Rejection::Prerelease(version_request.clone()));
+                }
+                _ => rejections.push(Rejection::Requirement(version_request.clone())),
+            }
+        }
+        let missing = Vec::from_iter(
+            self.with_feature
+                .iter()
+                .filter(|feature| !has_feature(version, feature))
+                .map(|feature| format!("`{feature}`")),
+        );
+        if !missing.is_empty() {
+            rejections.push(Rejection::MissingFeatures(missing.join(", ")));
+        }
+        rejections
+    }
+
+    /// Gets the crate file for the version, from the cargo cache if possible, verifying its checksum
+    #[culpa::throws]
+    fn fetch(
+        &self,
+        registry: &str,
+        config: &crates_index::IndexConfig,
+        version: &crates_index::Version,
+        bar: &indicatif::ProgressBar,
+        styles: &Styles,
+    ) -> CrateFile {
+        let version_str = stylish::format!(
+            "{:(fg=magenta)} {:(fg=magenta)}",
+            version.name(),
+            version.version()
+        );
+
+        let cached = if self.cache {
+            bar.set_message(stylish::ansi::format!(
+                "checking cache for {:s}",
+                version_str
+            ));
+            self.slow();
+            if registry == crates_index::sparse::URL {
+                cache::lookup_all(
+                    &[crates_index::sparse::URL, crates_index::git::URL],
+                    version,
+                )
+            } else {
+                cache::lookup_all(&[registry], version)
+            }
+        } else {
+            Err(anyhow!("cache disabled by flag"))
+        };
+
+        match cached {
+            Ok(path) => {
+                tracing::debug!(
+                    "found cached crate for {} {} at {}",
+                    version.name(),
+                    version.version(),
+                    path.display()
+                );
+                CrateFile::Cached(path)
+            }
+            Err(err) => {
+                use sha2::Digest;
+                tracing::debug!("{err:?}");
+                let url = version
+                    .download_url(config)
+                    .context("missing download url")?;
+                bar.set_message(stylish::ansi::format!("downloading {:s}", version_str));
+                let resp = ureq::get(&url).set("User-Agent", USER_AGENT).call()?;
+                let mut data;
+                if let Some(len) = resp
+                    .header("Content-Length")
+                    .and_then(|s| s.parse::<usize>().ok())
+                {
+                    data = Vec::with_capacity(len);
+                    bar.reset();
+                    bar.set_length(u64::try_from(len)?);
+                    bar.set_style(styles.download.clone());
+                } else {
+                    data = Vec::with_capacity(usize::try_from(CRATE_SIZE_LIMIT)?);
+                }
+                bar.wrap_read(resp.into_reader())
+                    .take(CRATE_SIZE_LIMIT)
+                    .read_to_end(&mut data)?;
+                self.slow();
+                tracing::debug!(
+                    "downloaded {} {} ({} bytes)",
+                    version.name(),
+                    version.version(),
+                    data.len()
+                );
+                bar.set_style(styles.spinner.clone());
+                bar.set_message(stylish::ansi::format!(
+                    "verifying checksum of {:s}",
+                    version_str
+                ));
+                let calculated_checksum = sha2::Sha256::digest(&data);
+                if calculated_checksum.as_slice() != version.checksum() {
                     tracing::debug!(
-                        "matching versions: {:?}",
-                        Vec::from_iter(versions.iter().map(|(num, _)| num.to_string()))
+                        "invalid checksum, expected {} but got {}",
+                        hex::encode(version.checksum()),
+                        hex::encode(calculated_checksum)
                     );
+                    bar.set_style(styles.failure.clone());
+                    bar.finish_with_message("invalid checksum");
+                    culpa::throw!(LoggedError);
+                }
+                tracing::debug!("verified checksum ({})", hex::encode(version.checksum()));
+                self.slow();
+                CrateFile::Data(data)
+            }
+        }
+    }
 
-                    let (_, version) = match versions.first() {
-                        Some(val) => val,
-                        None => {
-                            let yanked_versions = {
-                                let mut versions: Vec<_> = krate
-                                    .versions()
-                                    .iter()
-                                    .filter(|version| version.is_yanked())
-                                    .filter_map(|version| match semver::Version::parse(version.version()) {
-                                        Ok(num) => Some((num, version)),
-                                        Err(err) => {
-                                            tracing::warn!(
-                                                "Ignoring non-semver version {} {err:#?}",
-                                                version.version()
-                                            );
-                                            None
-                                        }
-                                    })
-                                    .filter(|(num, _)| version_request.matches(num))
-                                    .collect();
-                                versions.sort_by(|(a, _), (b, _)| a.cmp(b).reverse());
-                                versions
-                            };
-                            let mut msg = "no matching version found".to_owned();
-                            if let Some((_, version)) = yanked_versions.first() {
-                                use std::fmt::Write;
-                                write!(msg, "; the yanked version {} {} matched, use `--allow-yanked` to download it", version.name(), version.version())?;
-                            }
-                            bar.set_style(failure_style.clone());
-                            bar.finish_with_message(msg);
-                            return Err(LoggedError.into());
-                        }
-                    };
+    /// The config.json of the sparse mirror being exported
+    fn mirror_config(&self) -> Option<crates_index::IndexConfig> {
+        Some(registry::mirror_config(
+            self.mirror_url.as_deref()?,
+            self.mirror_dl.clone(),
+            self.mirror_api.clone(),
+        ))
+    }
+
+    /// Where the crate file is written or extracted to
+    fn output_path(&self, name: &str, version: &str) -> String {
+        self.output.clone().unwrap_or_else(|| {
+            if self.extract {
+                format!("{name}-{version}")
+            } else {
+                format!("{name}-{version}.crate")
+            }
+        })
+    }
+
+    /// Makes sure an existing output is only replaced or merged into when requested
+    #[culpa::throws]
+    fn check_output(&self, output: &Path) {
+        let Ok(metadata) = output.symlink_metadata() else {
+            return;
+        };
+        if !self.force && (!self.merge || !metadata.is_dir()) {
+            culpa::throw!(anyhow!(
+                "{} already exists, use --force to replace it{}",
+                output.display(),
+                if self.extract {
+                    " or --merge to extract into it"
+                } else {
+                    ""
+                }
+            ));
+        }
+    }
+
+    /// Moves a completely written output into place, replacing or merging into an existing
+    /// output
+    #[culpa::throws]
+    fn commit_output(&self, partial: atomic::Partial, output: &Path) {
+        if self.merge && output.is_dir() {
+            partial.merge()?;
+        } else {
+            partial.replace()?;
+        }
+    }
+
+    fn limits(&self) -> unpack::Limits {
+        unpack::Limits {
+            entries: self.max_entries,
+            bytes: self.max_unpacked_size,
+        }
+    }
+
+    fn unpack_options(&self) -> unpack::Options {
+        unpack::Options {
+            layout: match self.strip_components {
+                Some(count) => unpack::Layout::Strip(count),
+                None if self.keep_top_level => unpack::Layout::Strip(0),
+                None => unpack::Layout::Crate,
+            },
+            limits: self.limits(),
+            mode: self.mode,
+            mtime: self.mtime,
+            deny_unportable: self.deny_unportable,
+            original_manifest: self.original_manifest,
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+        }
+    }
+
+    /// Extracts the crate to a temporary sibling of the output
+    #[culpa::throws]
+    fn extract_partial(
+        &self,
+        name: &str,
+        version: &str,
+        file: &CrateFile,
+        output: &Path,
+        bar: &indicatif::ProgressBar,
+        styles: &Styles,
+    ) -> atomic::Partial {
+        let options = self.unpack_options();
+        unpack::validate(
+            name,
+            version,
+            tar::Archive::new(flate2::bufread::GzDecoder::new(file.reader()?)),
+            &options.layout,
+            options.limits,
+        )?;
+        bar.reset();
+        bar.set_length(file.len()?);
+        bar.set_style(styles.download.clone());
+        let archive = tar::Archive::new(flate2::bufread::GzDecoder::new(
+            bar.wrap_read(file.reader()?),
+        ));
+        let partial = atomic::Partial::new(output)?;
+        unpack::unpack(name, version, archive, partial.path(), &options)?;
+        partial
+    }
+
+    /// Prints the files matching `--cat`, once every entry is known to be where cargo expects it
+    #[culpa::throws]
+    fn cat_files(
+        &self,
+        name: &str,
+        version: &str,
+        file: &CrateFile,
+        out: &mut impl std::io::Write,
+    ) -> usize {
+        let options = self.unpack_options();
+        unpack::validate(
+            name,
+            version,
+            tar::Archive::new(flate2::bufread::GzDecoder::new(file.reader()?)),
+            &options.layout,
+            options.limits,
+        )?;
+        unpack::cat(
+            name,
+            version,
+            tar::Archive::new(flate2::bufread::GzDecoder::new(file.reader()?)),
+            &options.layout,
+            options.limits,
+            &self.cat,
+            out,
+        )?
+    }
+
+    /// Writes or extracts the crate file to the requested output, returning the extracted crate
+    /// instead of moving it into place when its locked dependencies are vendored into it first
+    #[culpa::throws]
+    fn deliver(
+        &self,
+        name: &str,
+        version: &str,
+        entry: Option<&crates_index::Version>,
+        file: &CrateFile,
+        bar: &indicatif::ProgressBar,
+        styles: &Styles,
+    ) -> Option<atomic::Partial> {
+        let version_str = stylish::format!("{:(fg=magenta)} {:(fg=magenta)}", name, version);
+        let checksum = entry.map(|entry| entry.checksum());
+
+        if let Some(format) = self.list_files {
+            bar.set_message(stylish::ansi::format!("listing files of {:s}", version_str));
+            let archive = tar::Archive::new(flate2::bufread::GzDecoder::new(file.reader()?));
+            let (listing, count, size) = list::list(name, version, archive, format, self.limits())?;
+            {
+                use std::io::Write;
+                std::io::stdout().lock().write_all(listing.as_bytes())?;
+            }
+            bar.set_style(styles.success.clone());
+            bar.finish_with_message(stylish::ansi::format!(
+                "listed {:(fg=blue)} entries ({:(fg=blue)} bytes) of {:s}",
+                count,
+                size,
+                version_str,
+            ));
+            return None;
+        }
+
+        if self.manifest_diff {
+            bar.set_message(stylish::ansi::format!(
+                "comparing manifests of {:s}",
+                version_str
+            ));
+            let archive = tar::Archive::new(flate2::bufread::GzDecoder::new(file.reader()?));
+            let diff = manifest::diff(name, version, archive, self.limits())?;
+            {
+                use std::io::Write;
+                std::io::stdout().lock().write_all(diff.as_bytes())?;
+            }
+            bar.set_style(styles.success.clone());
+            bar.finish_with_message(stylish::ansi::format!(
+                "compared manifests of {:s}",
+                version_str
+            ));
+            return None;
+        }
+
+        if !self.cat.is_empty() {
+            bar.set_message(stylish::ansi::format!(
+                "printing files from {:s}",
+                version_str
+            ));
+            let printed = self.cat_files(name, version, file, &mut std::io::stdout().lock())?;
+            bar.set_style(styles.success.clone());
+            let files = if printed == 1 { "file" } else { "files" };
+            bar.finish_with_message(stylish::ansi::format!(
+                "printed {:(fg=blue)} {} from {:s}",
+                printed,
+                files,
+                version_str
+            ));
+            return None;
+        }
+
+        let vendor_output = if let Some(vendor) = &self.vendor {
+            if !self.force {
+                for existing in [vendor.join(format!("{name}-{version}")), vendor.join(name)] {
+                    if existing.symlink_metadata().is_ok() {
+                        culpa::throw!(anyhow!(
+                            "{} already exists, use --force to replace it or --update-vendor to add to an existing vendor directory",
+                            existing.display()
+                        ));
+                    }
+                }
+            }
+            Some((vendor.join(format!("{name}-{version}")), None))
+        } else if let Some(vendor) = &self.update_vendor {
+            let existing = vendor::find(vendor, name)?;
+            let replaced = existing
+                .into_iter()
+                .find(|(_, existing)| vendor::replaces(existing, version));
+            if let Some((path, existing)) = &replaced {
+                // Only the copy being replaced needs to be unmodified, others are left alone
+                vendor::verify(path)?;
+                tracing::info!("replacing {name} {existing} in {}", path.display());
+            }
+            let output = vendor::update_path(
+                vendor,
+                name,
+                version,
+                replaced.as_ref().map(|(path, _)| path.as_path()),
+            );
+            Some((output, replaced.map(|(path, _)| path)))
+        } else {
+            None
+        };
+
+        if let Some((output, replaced)) = vendor_output {
+            bar.set_message(stylish::ansi::format!(
+                "vendoring {:s} to {:(fg=blue)}",
+                version_str,
+                output.display()
+            ));
+            let partial = self.extract_partial(name, version, file, &output, bar, styles)?;
+            vendor::write_checksums(partial.path(), checksum)?;
+            partial.replace()?;
+            if let Some(replaced) = replaced.filter(|replaced| *replaced != output) {
+                atomic::remove(&replaced)?;
+            }
+            self.slow();
+            bar.set_style(styles.success.clone());
+            bar.finish_with_message(stylish::ansi::format!(
+                "vendored {:s} to {:(fg=blue)}",
+                version_str,
+                output.display()
+            ));
+            return None;
+        }
+
+        if self.playground {
+            let output = playground::crate_dir(name, version)?;
+            if output.exists() {
+                bar.set_style(styles.success.clone());
+                bar.finish_with_message(stylish::ansi::format!(
+                    "using cached {:s} in {:(fg=blue)}",
+                    version_str,
+                    output.display()
+                ));
+                return None;
+            }
+            bar.set_message(stylish::ansi::format!(
+                "extracting {:s} to {:(fg=blue)}",
+                version_str,
+                output.display()
+            ));
+            self.extract_partial(name, version, file, &output, bar, styles)?
+                .commit()?;
+            self.slow();
+            bar.set_style(styles.success.clone());
+            bar.finish_with_message(stylish::ansi::format!(
+                "extracted {:s} to {:(fg=blue)}",
+                version_str,
+                output.display()
+            ));
+            return None;
+        }
+
+        if let Some((mirror, config)) = self.sparse_mirror.as_ref().zip(self.mirror_config()) {
+            let url = entry
+                .and_then(|entry| entry.download_url(&config))
+                .context("missing download url")?;
+            let Some(path) =
+                registry::mirror_path(self.mirror_url.as_deref().unwrap_or_default(), &url)
+            else {
+                tracing::info!("not writing {name} {version}, it is downloaded from {url}");
+                bar.set_style(styles.success.clone());
+                bar.finish_with_message(stylish::ansi::format!("indexed {:s}", version_str));
+                return None;
+            };
+            let output = mirror.join(path);
+            bar.set_message(stylish::ansi::format!(
+                "writing {:s} to {:(fg=blue)}",
+                version_str,
+                output.display()
+            ));
+            self.slow();
+            let partial = atomic::Partial::new(&output)?;
+            file.write_to(partial.path())?;
+            partial.commit()?;
+            bar.set_style(styles.success.clone());
+            bar.finish_with_message(stylish::ansi::format!(
+                "written {:s} to {:(fg=blue)}",
+                version_str,
+                output.display()
+            ));
+            return None;
+        }
+
+        if let Some(local_registry) = &self.local_registry {
+            let output = local_registry.join(format!("{name}-{version}.crate"));
+            bar.set_message(stylish::ansi::format!(
+                "writing {:s} to {:(fg=blue)}",
+                version_str,
+                output.display()
+            ));
+            self.slow();
+            let partial = atomic::Partial::new(&output)?;
+            file.write_to(partial.path())?;
+            partial.commit()?;
+            bar.set_style(styles.success.clone());
+            bar.finish_with_message(stylish::ansi::format!(
+                "written {:s} to {:(fg=blue)}",
+                version_str,
+                output.display()
+            ));
+            return None;
+        }
 
-                    let version_str = stylish::format!("{:(fg=magenta)} {:(fg=magenta)}", version.name(), version.version());
+        let output = self.output_path(name, version);
+        self.check_output(Path::new(&output))?;
 
-                    let output = self.output.clone().unwrap_or_else(|| if self.extract {
-                        format!("{}-{}", version.name(), version.version())
-                    } else {
-                        format!("{}-{}.crate", version.name(), version.version())
+        if self.extract {
+            bar.set_message(stylish::ansi::format!(
+                "extracting {:s} to {:(fg=blue)}",
+                version_str,
+                output
+            ));
+            let partial =
+                self.extract_partial(name, version, file, Path::new(&output), bar, styles)?;
+            if self.with_locked_deps {
+                bar.set_style(styles.success.clone());
+                bar.finish_with_message(stylish::ansi::format!(
+                    "extracted {:s}, vendoring its locked dependencies into {:(fg=blue)}",
+                    version_str,
+                    output
+                ));
+                return Some(partial);
+            }
+            self.commit_output(partial, Path::new(&output))?;
+            self.slow();
+            bar.set_style(styles.success.clone());
+            bar.finish_with_message(stylish::ansi::format!(
+                "extracted {:s} to {:(fg=blue)}",
+                version_str,
+                output
+            ));
+        } else {
+            bar.set_message(stylish::ansi::format!(
+                "writing {:s} to {:(fg=blue)}",
+                version_str,
+                output
+            ));
+            self.slow();
+            let partial = atomic::Partial::new(Path::new(&output))?;
+            file.write_to(partial.path())?;
+            self.commit_output(partial, Path::new(&output))?;
+            bar.set_style(styles.success.clone());
+            bar.finish_with_message(stylish::ansi::format!(
+                "written {:s} to {:(fg=blue)}",
+                version_str,
+                output
+            ));
+        }
+        None
+    }
+
+    /// Resolves the dependencies of all the registry crates being acquired
+    #[culpa::throws]
+    fn dependencies(
+        &self,
+        jobs: &mut [Job],
+        bars: &indicatif::MultiProgress,
+        styles: &Styles,
+    ) -> Vec<Job> {
+        let platform = match self.target.as_deref() {
+            Some("all") => cfg::Platform::All,
+            target => cfg::Platform::target(target)?,
+        };
+        let options = deps::Options {
+            dev: self.dev_deps,
+            features: &self.features,
+            all_features: self.all_features,
+            no_default_features: self.no_default_features,
+            platform: &platform,
+        };
+
+        let mut roots = Vec::new();
+        for job in jobs.iter_mut() {
+            let Source::Registry(registry) = self.source(&job.spec) else {
+                tracing::warn!(
+                    "not resolving dependencies of {}, only registry crates are supported",
+                    job.spec
+                );
+                continue;
+            };
+            let bar = bars
+                .add(interrupt::track(indicatif::ProgressBar::new_spinner()))
+                .with_style(styles.spinner.clone())
+                .with_prefix(job.spec.to_string());
+            bar.enable_steady_tick(Duration::from_millis(100));
+            let version = self.select_version(&job.spec, job.locked, &registry, &bar, styles)?;
+            bar.finish_and_clear();
+            // The job downloads the version selected here, rather than selecting it again
+            job.selected = Some(version.clone());
+            roots.push((registry, version));
+        }
+        if roots.is_empty() {
+            return Vec::new();
+        }
+
+        let bar = bars
+            .add(interrupt::track(indicatif::ProgressBar::new_spinner()))
+            .with_style(styles.spinner.clone())
+            .with_prefix("dependencies");
+        bar.enable_steady_tick(Duration::from_millis(100));
+        let mut indexes = std::collections::HashMap::new();
+        let resolved = deps::resolve(
+            &roots,
+            &options,
+            |registry, name| {
+                let index = match indexes.entry(registry.to_owned()) {
+                    std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                    std::collections::hash_map::Entry::Vacant(entry) => {
+                        entry.insert(open_index(registry)?)
+                    }
+                };
+                Ok(find_crate_in_index(self, index, name, &bar)?.map(|(_, krate)| krate))
+            },
+            index_cache::registries,
+        )?;
+        bar.set_style(styles.success.clone());
+        bar.finish_with_message(format!("resolved {} dependencies", resolved.len()));
+        Vec::from_iter(resolved.into_iter().map(|(registry, version)| Job {
+            spec: PackageIdSpec {
+                source: Some(Source::Registry(registry)),
+                name: CrateName(version.name().to_owned()),
+                version_req: Some(VersionReq::Exact(version.version().to_owned())),
+            },
+            checksum: None,
+            locked: false,
+            selected: Some(version),
+        }))
+    }
+
+    /// Acquires every requested crate, returning those that were delivered
+    #[culpa::throws]
+    fn acquire(&'static self) -> Vec<Delivered> {
+        let mut specs = Vec::new();
+        for spec in self.specs.iter().cloned() {
+            spec_file::push_unique(&mut specs, spec);
+        }
+        if let Some(path) = &self.from_file {
+            for spec in spec_file::read(path)? {
+                spec_file::push_unique(&mut specs, spec);
+            }
+        }
+
+        let mut jobs = Vec::new();
+        if self.from_workspace {
+            let workspace = workspace::Workspace::find(&std::env::current_dir()?)?;
+            for spec in &specs {
+                if spec.source.is_some() {
+                    jobs.push(Job {
+                        spec: spec.clone(),
+                        checksum: None,
+                        locked: false,
+                        selected: None,
                     });
+                } else {
+                    jobs.extend(workspace.resolve(spec)?.into_iter().map(|locked| Job {
+                        spec: locked.spec,
+                        checksum: locked.checksum,
+                        locked: locked.from_lockfile,
+                        selected: None,
+                    }));
+                }
+            }
+        } else {
+            jobs.extend(specs.iter().map(|spec| Job {
+                spec: spec.clone(),
+                checksum: None,
+                locked: false,
+                selected: None,
+            }));
+        }
+        if let Some(path) = &self.lockfile {
+            jobs.extend(lockfile::read(path)?.into_iter().map(|locked| Job {
+                spec: locked.spec,
+                checksum: locked.checksum,
+                locked: locked.from_lockfile,
+                selected: None,
+            }));
+        }
 
-                    let cached = if self.cache {
-                        bar.set_message(stylish::ansi::format!("checking cache for {:s}", version_str));
-                        self.slow();
-                        cache::lookup_all(&[crates_index::sparse::URL, crates_index::git::URL], version)
-                    } else {
-                        Err(anyhow!("cache disabled by flag"))
-                    };
+        let styles: &Styles = Box::leak(Box::new(Styles::new()?));
+        let bars: &indicatif::MultiProgress = Box::leak(Box::new(indicatif::MultiProgress::new()));
 
-                    match cached {
-                        Ok(path) => {
-                            tracing::debug!("found cached crate for {} {} at {}", version.name(), version.version(), path.display());
-                            if self.extract {
-                                bar.set_message(stylish::ansi::format!("extracting {:s} to {:(fg=blue)}", version_str, output));
-                                let file = std::fs::File::open(path)?;
-                                bar.reset();
-                                bar.set_length(file.metadata()?.len());
-                                bar.set_style(download_style.clone());
-                                let archive = tar::Archive::new(flate2::bufread::GzDecoder::new(bar.wrap_read(std::io::BufReader::new(file))));
-                                unpack::unpack(version, archive, &output)?;
-                                self.slow();
-                                bar.set_style(success_style.clone());
-                                bar.finish_with_message(stylish::ansi::format!("extracted {:s} to {:(fg=blue)}", version_str, output));
-                            } else {
-                                bar.set_message(stylish::ansi::format!("writing {:s} to {:(fg=blue)}", version_str, output));
-                                self.slow();
-                                std::fs::copy(path, &output)?;
-                                bar.set_style(success_style.clone());
-                                bar.finish_with_message(stylish::ansi::format!("written {:s} to {:(fg=blue)}", version_str, output));
-                            }
+        if self.deps {
+            let dependencies = self.dependencies(&mut jobs, bars, styles)?;
+            jobs.extend(dependencies);
+        }
+        let jobs: &[Job] = Box::leak(jobs.into_boxed_slice());
+
+        if jobs.len() > 1 && self.output.is_some() {
+            culpa::throw!(anyhow!("cannot use --output with multiple crates"));
+        }
+
+        if (self.local_registry.is_some() || self.sparse_mirror.is_some())
+            && jobs
+                .iter()
+                .any(|job| matches!(self.source(&job.spec), Source::Git(_)))
+        {
+            culpa::throw!(anyhow!("git crates cannot be added to a registry"));
+        }
+
+        let thread = std::thread::spawn(move || {
+            let configs = {
+                let mut configs = std::collections::HashMap::new();
+                for source in std::collections::BTreeSet::from_iter(
+                    jobs.iter().map(|job| self.source(&job.spec)),
+                ) {
+                    let Source::Registry(registry) = source else {
+                        continue;
+                    };
+                    let bar = bars
+                        .add(interrupt::track(indicatif::ProgressBar::new_spinner()))
+                        .with_style(styles.spinner.clone())
+                        .with_prefix("index")
+                        .with_message(stylish::ansi::format!(
+                            "downloading {:(fg=magenta)} for {:(fg=blue)}",
+                            "config.json",
+                            registry,
+                        ));
+                    bar.enable_steady_tick(Duration::from_millis(100));
+                    // Only the crates from this registry fail if its index is unusable
+                    match open_index(&registry).and_then(|index| config(self, &index)) {
+                        Ok(config) => {
+                            configs.insert(registry, config);
                         }
-                        Err(err) => {
-                            use sha2::Digest;
-                            tracing::debug!("{err:?}");
-                            let url = version.download_url(config).context("missing download url")?;
-                            bar.set_message(stylish::ansi::format!("downloading {:s}", version_str));
-                            let resp = ureq::get(&url).set("User-Agent", USER_AGENT).call()?;
-                            let mut data;
-                            if let Some(len) = resp.header("Content-Length").and_then(|s| s.parse::<usize>().ok()) {
-                                data = Vec::with_capacity(len);
-                                bar.reset();
-                                bar.set_length(u64::try_from(len)?);
-                                bar.set_style(download_style.clone());
-                            } else {
-                                data = Vec::with_capacity(usize::try_from(CRATE_SIZE_LIMIT)?);
-                            }
-                            bar.wrap_read(resp.into_reader()).take(CRATE_SIZE_LIMIT).read_to_end(&mut data)?;
-                            self.slow();
-                            tracing::debug!("downloaded {} {} ({} bytes)", version.name(), version.version(), data.len());
-                            bar.set_style(spinner_style.clone());
-                            bar.set_message(stylish::ansi::format!("verifying checksum of {:s}", version_str));
-                            let calculated_checksum = sha2::Sha256::digest(&data);
-                            if calculated_checksum.as_slice() != version.checksum() {
-                                tracing::debug!("invalid checksum, expected {} but got {}", hex::encode(version.checksum()), hex::encode(calculated_checksum));
-                                bar.set_style(failure_style.clone());
-                                bar.finish_with_message("invalid checksum");
-                                return Err(LoggedError.into());
-                            }
-                            tracing::debug!("verified checksum ({})", hex::encode(version.checksum()));
-                            self.slow();
-
-                            if self.extract {
-                                bar.set_message(stylish::ansi::format!("extracting {:s} to {:(fg=blue)}", version_str, output));
-                                bar.reset();
-                                bar.set_length(u64::try_from(data.len())?);
-                                bar.set_style(download_style.clone());
-                                let archive = tar::Archive::new(flate2::bufread::GzDecoder::new(bar.wrap_read(std::io::Cursor::new(data))));
-                                unpack::unpack(version, archive, &output)?;
-                                self.slow();
-                                bar.set_style(success_style.clone());
-                                bar.finish_with_message(stylish::ansi::format!("extracted {:s} to {:(fg=blue)}", version_str, output));
-                            } else {
-                                bar.set_message(stylish::ansi::format!("writing {:s} to {:(fg=blue)}", version_str, output));
-                                std::fs::write(&output, data)?;
-                                self.slow();
-                                bar.set_style(success_style.clone());
-                                bar.finish_with_message(stylish::ansi::format!("written {:s} to {:(fg=blue)}", version_str, output));
-                            }
+                        Err(e) => {
+                            tracing::debug!("{e:?}");
+                            bar.set_style(styles.failure.clone());
+                            bar.finish_with_message(format!("{e:#}"));
                         }
                     }
-                    Result::<(), anyhow::Error>::Ok(())
-                }))
-            }));
+                }
+                &*Box::leak(Box::new(configs))
+            };
+
+            // The exact crates already being acquired, so that specs resolving to the same
+            // version only acquire it once
+            let claimed: &std::sync::Mutex<std::collections::BTreeSet<(Source, String, String)>> =
+                Box::leak(Box::default());
+            let threads = Vec::from_iter(jobs.iter().map(
+                |Job {
+                     spec,
+                     checksum,
+                     locked,
+                     selected,
+                 }| {
+                    let bar = bars
+                        .add(interrupt::track(indicatif::ProgressBar::new_spinner()))
+                        .with_style(styles.spinner.clone());
+                    (
+                        spec,
+                        std::thread::spawn(move || {
+                            bar.tick();
+                            bar.set_prefix(spec.to_string());
+                            bar.set_style(styles.spinner.clone());
+                            bar.enable_steady_tick(Duration::from_millis(100));
+
+                            let source = self.source(spec);
+                            let claim = |name: &str, version: &str| {
+                                let claimed = claimed.lock().unwrap().insert((
+                                    source.clone(),
+                                    name.to_owned(),
+                                    version.to_owned(),
+                                ));
+                                if !claimed {
+                                    bar.set_style(styles.success.clone());
+                                    bar.finish_with_message(stylish::ansi::format!(
+                                        "skipped {:(fg=magenta)} {:(fg=magenta)}, already acquired by another spec",
+                                        name,
+                                        version,
+                                    ));
+                                }
+                                claimed
+                            };
+                            let (name, version, entry, file) = match &source {
+                                Source::Registry(registry) => {
+                                    let Some(config) = configs.get(registry) else {
+                                        bar.set_style(styles.failure.clone());
+                                        bar.finish_with_message(
+                                            "could not use the index, see above",
+                                        );
+                                        culpa::throw!(LoggedError);
+                                    };
+                                    let version = match selected {
+                                        Some(version) => version.clone(),
+                                        None => self.select_version(
+                                            spec, *locked, registry, &bar, styles,
+                                        )?,
+                                    };
+                                    if let Some(checksum) =
+                                        checksum.filter(|checksum| checksum != version.checksum())
+                                    {
+                                        tracing::debug!(
+                                            "locked checksum {} but index has {}",
+                                            hex::encode(checksum),
+                                            hex::encode(version.checksum())
+                                        );
+                                        bar.set_style(styles.failure.clone());
+                                        bar.finish_with_message(
+                                            "locked checksum does not match the index",
+                                        );
+                                        culpa::throw!(LoggedError);
+                                    }
+                                    if !claim(version.name(), version.version()) {
+                                        return Ok(None);
+                                    }
+                                    let file =
+                                        self.fetch(registry, config, &version, &bar, styles)?;
+                                    (
+                                        version.name().to_owned(),
+                                        version.version().to_owned(),
+                                        Some(version),
+                                        file,
+                                    )
+                                }
+                                Source::Git(git) => {
+                                    let (version, data) = git::package(
+                                        git,
+                                        &spec.name.0,
+                                        spec.version_req.as_ref(),
+                                        &bar,
+                                    )?;
+                                    if !claim(&spec.name.0, &version.to_string()) {
+                                        return Ok(None);
+                                    }
+                                    (
+                                        spec.name.0.clone(),
+                                        version.to_string(),
+                                        None,
+                                        CrateFile::Data(data),
+                                    )
+                                }
+                            };
+
+                            let extracted = self.deliver(
+                                &name,
+                                &version,
+                                entry.as_ref(),
+                                &file,
+                                &bar,
+                                styles,
+                            )?;
+                            Result::<_, anyhow::Error>::Ok(Some(Delivered {
+                                name,
+                                version,
+                                source,
+                                entry,
+                                extracted,
+                            }))
+                        }),
+                    )
+                },
+            ));
             Result::<_, anyhow::Error>::Ok(threads)
         });
         let mut logged_error = false;
+        let mut delivered = Vec::new();
         match thread.join() {
             Ok(threads) => {
                 for (spec, thread) in threads? {
                     match thread.join() {
-                        Ok(Ok(())) => (),
+                        Ok(Ok(krate)) => delivered.extend(krate),
                         Ok(Err(e)) => {
                             if e.is::<LoggedError>() {
                                 logged_error = true;
@@ -384,6 +1608,189 @@ impl App {
         if logged_error {
             culpa::throw!(LoggedError);
         }
+        delivered
+    }
+
+    /// The options for vendoring the dependencies locked in `lockfile` into `vendor`; only the
+    /// settings for fetching crates apply to them, not how the crate itself is delivered
+    fn locked_deps_app(&self, lockfile: PathBuf, vendor: PathBuf) -> App {
+        App {
+            extract: false,
+            output: None,
+            force: self.force,
+            merge: false,
+            cat: Vec::new(),
+            list_files: None,
+            manifest_diff: false,
+            original_manifest: false,
+            strip_components: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            keep_top_level: false,
+            max_entries: self.max_entries,
+            max_unpacked_size: self.max_unpacked_size,
+            mode: None,
+            read_only: false,
+            mtime: unpack::Mtime::Archive,
+            deny_unportable: false,
+            vendor: Some(vendor),
+            update_vendor: None,
+            local_registry: None,
+            sparse_mirror: None,
+            mirror_url: None,
+            mirror_dl: None,
+            mirror_api: None,
+            playground: false,
+            playground_examples: false,
+            specs: Vec::new(),
+            from_file: None,
+            lockfile: Some(lockfile),
+            from_workspace: false,
+            with_locked_deps: false,
+            deps: false,
+            dev_deps: false,
+            features: Vec::new(),
+            all_features: false,
+            no_default_features: false,
+            target: None,
+            git: None,
+            rev: None,
+            branch: None,
+            tag: None,
+            allow_yanked: false,
+            with_feature: Vec::new(),
+            explain: false,
+            lenient_versions: self.lenient_versions,
+            cache: self.cache,
+            update_index: self.update_index,
+            slooooow: self.slooooow,
+        }
+    }
+
+    /// Vendors the dependencies locked in the Cargo.lock of a crate extracted to `dir`, and
+    /// configures cargo to build the crate using them
+    #[culpa::throws]
+    #[fn_error_context::context("vendoring the locked dependencies of {} {}", krate.name, krate.version)]
+    fn vendor_locked_deps(&self, krate: &Delivered, dir: &Path) {
+        let lockfile = dir.join("Cargo.lock");
+        if !lockfile.is_file() {
+            tracing::info!(
+                "{} {} has no Cargo.lock, not vendoring its dependencies",
+                krate.name,
+                krate.version
+            );
+            return;
+        }
+        let vendor = dir.join("vendor");
+        let app: &'static App = Box::leak(Box::new(self.locked_deps_app(lockfile, vendor.clone())));
+        let delivered = app.acquire()?;
+        let config = finish_vendor(&vendor, "vendor", &delivered, self.force)?;
+        atomic::write(&dir.join(".cargo").join("config.toml"), config)?;
+    }
+
+    #[culpa::throws]
+    #[tracing::instrument(fields(%self))]
+    fn run(&'static self) {
+        interrupt::install(Styles::new()?.failure)?;
+        let mut delivered = self.acquire()?;
+
+        if let Some(vendor) = &self.vendor {
+            let config = finish_vendor(
+                vendor,
+                &vendor.display().to_string(),
+                &delivered,
+                self.force,
+            )?;
+            println!("To use the vendored sources, add this to your .cargo/config.toml:\n");
+            print!("{config}");
+        }
+
+        if self.with_locked_deps {
+            for krate in &mut delivered {
+                if let Some(partial) = krate.extracted.take() {
+                    self.vendor_locked_deps(krate, partial.path())?;
+                    let output = self.output_path(&krate.name, &krate.version);
+                    self.commit_output(partial, Path::new(&output))?;
+                }
+            }
+        }
+
+        // Last, so that nothing else needs to write into the extracted crates
+        if self.read_only {
+            for krate in &delivered {
+                unpack::make_read_only(Path::new(&self.output_path(&krate.name, &krate.version)))?;
+            }
+        }
+
+        if self.playground {
+            for krate in &delivered {
+                let project = match &self.output {
+                    Some(output) => PathBuf::from(output),
+                    None => playground::project_dir(&krate.name, &krate.version)?,
+                };
+                self.check_output(&project)?;
+                let crate_dir = playground::crate_dir(&krate.name, &krate.version)?;
+                let partial = atomic::Partial::new(&project)?;
+                let bins = playground::create(
+                    &crate_dir,
+                    &krate.name,
+                    &krate.version,
+                    partial.path(),
+                    self.playground_examples,
+                )?;
+                self.commit_output(partial, &project)?;
+                println!(
+                    "Created playground for {} {} at {}",
+                    krate.name,
+                    krate.version,
+                    project.display()
+                );
+                if !bins.is_empty() {
+                    println!(
+                        "Run the examples with `cargo run --bin <example>`: {}",
+                        bins.join(", ")
+                    );
+                }
+            }
+        }
+
+        if let Some(local_registry) = &self.local_registry {
+            for (registry, entry) in delivered.iter().filter_map(Delivered::registry_entry) {
+                registry::add_entry(&local_registry.join("index"), registry, entry)?;
+            }
+            let sources = std::collections::BTreeSet::from_iter(
+                delivered.iter().map(|krate| krate.source.clone()),
+            );
+            println!("To use the local registry, add this to your .cargo/config.toml:\n");
+            print!(
+                "{}",
+                vendor::config(
+                    "local-registry",
+                    "local-registry",
+                    &local_registry.display().to_string(),
+                    &sources
+                )?
+            );
+        }
+
+        if let Some((mirror, config)) = self.sparse_mirror.as_ref().zip(self.mirror_config()) {
+            registry::write_config(mirror, &config)?;
+            for (registry, entry) in delivered.iter().filter_map(Delivered::registry_entry) {
+                registry::add_entry(mirror, registry, entry)?;
+            }
+            let sources = std::collections::BTreeSet::from_iter(
+                delivered.iter().map(|krate| krate.source.clone()),
+            );
+            let url = format!(
+                "sparse+{}/",
+                self.mirror_url
+                    .as_deref()
+                    .unwrap_or_default()
+                    .trim_end_matches('/')
+            );
+            println!("To use the mirror once served, add this to your .cargo/config.toml:\n");
+            print!("{}", vendor::config("mirror", "registry", &url, &sources)?);
+        }
     }
 }
 
@@ -394,12 +1801,132 @@ impl std::fmt::Display for App {
         if self.allow_yanked {
             write!(f, " --allow-yanked")?;
         }
+        if self.lenient_versions {
+            write!(f, " --lenient-versions")?;
+        }
+        if self.explain {
+            write!(f, " --explain")?;
+        }
+        for feature in &self.with_feature {
+            write!(f, " --with-feature={:?}", feature)?;
+        }
         if self.extract {
             write!(f, " --extract")?;
         }
         if let Some(output) = &self.output {
             write!(f, " --output={:?}", output)?;
         }
+        if self.force {
+            write!(f, " --force")?;
+        }
+        for glob in &self.cat {
+            write!(f, " --cat={glob}")?;
+        }
+        if let Some(format) = self.list_files {
+            write!(f, " --list-files={format}")?;
+        }
+        if self.manifest_diff {
+            write!(f, " --manifest-diff")?;
+        }
+        if self.original_manifest {
+            write!(f, " --original-manifest")?;
+        }
+        if let Some(count) = self.strip_components {
+            write!(f, " --strip-components={count}")?;
+        }
+        if self.keep_top_level {
+            write!(f, " --keep-top-level")?;
+        }
+        for glob in &self.include {
+            write!(f, " --include={glob}")?;
+        }
+        for glob in &self.exclude {
+            write!(f, " --exclude={glob}")?;
+        }
+        if self.merge {
+            write!(f, " --merge")?;
+        }
+        if let Some(mode) = self.mode {
+            write!(f, " --mode={mode}")?;
+        }
+        if self.read_only {
+            write!(f, " --read-only")?;
+        }
+        if self.deny_unportable {
+            write!(f, " --deny-unportable")?;
+        }
+        if self.mtime != unpack::Mtime::Archive {
+            write!(f, " --mtime={}", self.mtime)?;
+        }
+        if let Some(vendor) = &self.vendor {
+            write!(f, " --vendor={:?}", vendor)?;
+        }
+        if let Some(vendor) = &self.update_vendor {
+            write!(f, " --update-vendor={:?}", vendor)?;
+        }
+        if let Some(local_registry) = &self.local_registry {
+            write!(f, " --local-registry={:?}", local_registry)?;
+        }
+        if self.playground {
+            write!(f, " --playground")?;
+        }
+        if self.playground_examples {
+            write!(f, " --playground-examples")?;
+        }
+        if let Some(mirror) = &self.sparse_mirror {
+            write!(f, " --sparse-mirror={:?}", mirror)?;
+        }
+        if let Some(url) = &self.mirror_url {
+            write!(f, " --mirror-url={:?}", url)?;
+        }
+        if let Some(dl) = &self.mirror_dl {
+            write!(f, " --mirror-dl={:?}", dl)?;
+        }
+        if let Some(api) = &self.mirror_api {
+            write!(f, " --mirror-api={:?}", api)?;
+        }
+        if let Some(lockfile) = &self.lockfile {
+            write!(f, " --lockfile={:?}", lockfile)?;
+        }
+        if self.from_workspace {
+            write!(f, " --from-workspace")?;
+        }
+        if self.with_locked_deps {
+            write!(f, " --with-locked-deps")?;
+        }
+        if self.deps {
+            write!(f, " --deps")?;
+        }
+        if self.dev_deps {
+            write!(f, " --dev-deps")?;
+        }
+        if !self.features.is_empty() {
+            write!(f, " --features={:?}", self.features.join(","))?;
+        }
+        if self.all_features {
+            write!(f, " --all-features")?;
+        }
+        if self.no_default_features {
+            write!(f, " --no-default-features")?;
+        }
+        if let Some(target) = &self.target {
+            write!(f, " --target={:?}", target)?;
+        }
+        if let Some(git) = &self.git {
+            write!(f, " --git={:?}", git)?;
+        }
+        if let Some(rev) = &self.rev {
+            write!(f, " --rev={:?}", rev)?;
+        }
+        if let Some(branch) = &self.branch {
+            write!(f, " --branch={:?}", branch)?;
+        }
+        if let Some(tag) = &self.tag {
+            write!(f, " --tag={:?}", tag)?;
+        }
+        if let Some(path) = &self.from_file {
+            write!(f, " --from-file={:?}", path)?;
+        }
         write!(f, " --")?;
         for spec in &self.specs {
             write!(f, " {}", spec)?;
@@ -481,3 +2008,328 @@ fn main() {
         Err(e) => e.exit(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{explain, has_feature, App, CrateFile, Delivered, Rejection};
+    use crate::version::{Version, VersionReq};
+    use crate::{atomic, package_id_spec::Source};
+    use clap::Parser;
+    use std::path::Path;
+
+    fn version(vers: &str, yanked: bool) -> crates_index::Version {
+        serde_json::from_value(serde_json::json!({
+            "name": "foo",
+            "vers": vers,
+            "deps": [],
+            "cksum": "0000000000000000000000000000000000000000000000000000000000000000",
+            "features": {},
+            "yanked": yanked,
+        }))
+        .unwrap()
+    }
+
+    /// A version with a `json` feature enabling an optional dependency through `dep:` syntax in
+    /// `features2`, and an optional dependency implicitly defining its own feature
+    fn featured_version() -> crates_index::Version {
+        let line = serde_json::json!({
+            "name": "foo",
+            "vers": "1.0.0",
+            "deps": [
+                {
+                    "name": "serde_json",
+                    "req": "^1",
+                    "features": [],
+                    "optional": true,
+                    "default_features": true,
+                    "target": null,
+                    "kind": "normal"
+                },
+                {
+                    "name": "rand",
+                    "req": "^0.8",
+                    "features": [],
+                    "optional": true,
+                    "default_features": true,
+                    "target": null,
+                    "kind": "normal"
+                }
+            ],
+            "cksum": "0000000000000000000000000000000000000000000000000000000000000000",
+            "features": { "std": [] },
+            "features2": { "json": ["dep:serde_json"] },
+            "yanked": false,
+        });
+        crates_index::Crate::from_slice(line.to_string().as_bytes())
+            .unwrap()
+            .versions()[0]
+            .clone()
+    }
+
+    fn rejections(
+        args: &[&str],
+        vers: &str,
+        yanked: bool,
+        req: &str,
+        locked: bool,
+    ) -> Vec<Rejection> {
+        let app = App::try_parse_from(["cargo-dl", "foo"].into_iter().chain(args.iter().copied()))
+            .unwrap();
+        app.rejections(
+            &Version::parse(vers),
+            &version(vers, yanked),
+            &req.parse::<VersionReq>().unwrap(),
+            locked,
+        )
+    }
+
+    #[test]
+    fn yanked_versions() {
+        assert!(matches!(
+            rejections(&[], "1.1.0", true, "^1", false)[..],
+            [Rejection::Yanked]
+        ));
+        assert!(rejections(&["--allow-yanked"], "1.1.0", true, "^1", false).is_empty());
+    }
+
+    #[test]
+    fn locked_yanked_versions() {
+        assert!(rejections(&[], "1.1.0", true, "=1.1.0", true).is_empty());
+        assert!(matches!(
+            rejections(&[], "1.1.0", true, "=1.0.0", true)[..],
+            [Rejection::Requirement(_)]
+        ));
+    }
+
+    #[test]
+    fn prereleases() {
+        assert!(matches!(
+            rejections(&[], "2.0.0-beta.1", false, "^2", false)[..],
+            [Rejection::Prerelease(_)]
+        ));
+        assert!(rejections(&[], "2.0.0-beta.1", false, "^2.0.0-beta", false).is_empty());
+    }
+
+    #[test]
+    fn non_semver_versions() {
+        assert!(matches!(
+            rejections(&[], "0.2.0.1", false, "=0.2.0.1", false)[..],
+            [Rejection::NonSemver]
+        ));
+        assert!(
+            rejections(&["--lenient-versions"], "0.2.0.1", false, "=0.2.0.1", false).is_empty()
+        );
+    }
+
+    #[test]
+    fn features() {
+        let version = featured_version();
+        assert!(has_feature(&version, "std"));
+        assert!(has_feature(&version, "json"));
+        assert!(has_feature(&version, "rand"));
+        assert!(!has_feature(&version, "serde_json"));
+        assert!(!has_feature(&version, "alloc"));
+    }
+
+    #[test]
+    fn missing_features() {
+        let app = App::try_parse_from([
+            "cargo-dl",
+            "foo",
+            "--with-feature=json",
+            "--with-feature=serde_json",
+            "--with-feature=alloc",
+        ])
+        .unwrap();
+        let version = featured_version();
+        let num = Version::parse(version.version());
+        let rejections = app.rejections(&num, &version, &VersionReq::STAR, false);
+        assert!(matches!(
+            &rejections[..],
+            [Rejection::MissingFeatures(missing)] if missing == "`serde_json`, `alloc`"
+        ));
+        assert_eq!(
+            app.no_match(&"foo".parse().unwrap(), &[(num, &version, rejections)])
+                .unwrap(),
+            "no matching version found; the newest matching version foo 1.0.0 is missing feature `serde_json`, `alloc`"
+        );
+    }
+
+    #[test]
+    fn explanations() {
+        let app = App::try_parse_from(["cargo-dl", "foo"]).unwrap();
+        let req = "^1".parse::<VersionReq>().unwrap();
+        let versions = [
+            version("1.2.0-rc.1", false),
+            version("1.1.0", true),
+            version("1.0.1", false),
+            version("1.0.0", false),
+            version("0.9.0", true),
+        ];
+        let candidates = Vec::from_iter(versions.iter().map(|version| {
+            let num = Version::parse(version.version());
+            let rejections = app.rejections(&num, version, &req, false);
+            (num, version, rejections)
+        }));
+        assert_eq!(
+            explain("foo", &req, &candidates, Some(2)).unwrap(),
+            "\
+foo: selecting from 5 versions with requirement `^1`
+        1.2.0-rc.1  rejected, pre-release not explicitly requested by `^1`
+             1.1.0  rejected, yanked, use `--allow-yanked` to allow it
+             1.0.1  selected, the newest of 2 accepted versions
+             1.0.0  accepted, but older than the selected version
+             0.9.0  rejected, yanked, use `--allow-yanked` to allow it; does not match `^1`
+"
+        );
+    }
+
+    #[test]
+    fn locked_deps_options() {
+        let app = App::try_parse_from([
+            "cargo-dl",
+            "foo",
+            "--extract",
+            "--output=out",
+            "--with-locked-deps",
+            "--force",
+            "--read-only",
+            "--max-entries=10",
+            "--no-cache",
+            "--lenient-versions",
+            "--with-feature=json",
+        ])
+        .unwrap();
+        let deps = app.locked_deps_app("out/Cargo.lock".into(), "out/vendor".into());
+        assert_eq!(deps.lockfile.as_deref(), Some("out/Cargo.lock".as_ref()));
+        assert_eq!(deps.vendor.as_deref(), Some("out/vendor".as_ref()));
+        assert!(deps.specs.is_empty());
+        assert!(deps.force && deps.lenient_versions && !deps.cache);
+        assert_eq!(deps.max_entries, 10);
+        assert!(!deps.extract && !deps.read_only && !deps.with_locked_deps);
+        assert!(deps.output.is_none() && deps.with_feature.is_empty());
+    }
+
+    #[test]
+    fn locked_deps_without_lockfile() {
+        let app =
+            App::try_parse_from(["cargo-dl", "foo", "--extract", "--with-locked-deps"]).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let krate = Delivered {
+            name: "foo".into(),
+            version: "1.0.0".into(),
+            source: Source::Registry("sparse+https://index.crates.io/".into()),
+            entry: None,
+            extracted: None,
+        };
+        app.vendor_locked_deps(&krate, dir.path()).unwrap();
+        assert!(std::fs::read_dir(dir.path()).unwrap().next().is_none());
+    }
+
+    fn output_app(args: &[&str], output: &Path) -> App {
+        App::try_parse_from(
+            ["cargo-dl", "foo", "--output", output.to_str().unwrap()]
+                .into_iter()
+                .chain(args.iter().copied()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn existing_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("foo-1.0.0.crate");
+        std::fs::write(&file, "old").unwrap();
+
+        let error = output_app(&[], &file).check_output(&file).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{} already exists, use --force to replace it",
+                file.display()
+            )
+        );
+        output_app(&["--force"], &file).check_output(&file).unwrap();
+        let missing = dir.path().join("bar-1.0.0.crate");
+        output_app(&[], &missing).check_output(&missing).unwrap();
+
+        // Only directories can be merged into
+        assert!(output_app(&["-x", "--merge"], &file)
+            .check_output(&file)
+            .is_err());
+    }
+
+    #[test]
+    fn existing_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("foo-1.0.0");
+        std::fs::create_dir(&output).unwrap();
+        std::fs::write(output.join("old.rs"), "").unwrap();
+
+        let error = output_app(&["-x"], &output)
+            .check_output(&output)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{} already exists, use --force to replace it or --merge to extract into it",
+                output.display()
+            )
+        );
+
+        let merge = output_app(&["-x", "--merge"], &output);
+        merge.check_output(&output).unwrap();
+        let partial = atomic::Partial::new(&output).unwrap();
+        std::fs::create_dir(partial.path()).unwrap();
+        std::fs::write(partial.path().join("new.rs"), "").unwrap();
+        merge.commit_output(partial, &output).unwrap();
+        assert!(output.join("old.rs").is_file() && output.join("new.rs").is_file());
+
+        let force = output_app(&["-x", "--force"], &output);
+        force.check_output(&output).unwrap();
+        let partial = atomic::Partial::new(&output).unwrap();
+        std::fs::create_dir(partial.path()).unwrap();
+        std::fs::write(partial.path().join("newer.rs"), "").unwrap();
+        force.commit_output(partial, &output).unwrap();
+        assert_eq!(
+            Vec::from_iter(
+                std::fs::read_dir(&output)
+                    .unwrap()
+                    .map(|entry| entry.unwrap().file_name())
+            ),
+            ["newer.rs"]
+        );
+    }
+
+    fn crate_file(entries: &[(&str, &str)]) -> CrateFile {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        for (path, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        CrateFile::Data(builder.into_inner().unwrap().finish().unwrap())
+    }
+
+    #[test]
+    fn cat_validates_the_layout() {
+        let app = App::try_parse_from(["cargo-dl", "foo", "--cat=src/lib.rs"]).unwrap();
+        let mut out = Vec::new();
+        let file = crate_file(&[("foo-1.0.0/src/lib.rs", "lib\n")]);
+        assert_eq!(app.cat_files("foo", "1.0.0", &file, &mut out).unwrap(), 1);
+        assert_eq!(out, b"lib\n");
+
+        let mut out = Vec::new();
+        let file = crate_file(&[("foo-1.0.0/src/lib.rs", "lib\n"), ("README", "")]);
+        let error = app.cat_files("foo", "1.0.0", &file, &mut out).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("1 entry in the archive is misplaced:\n  README is at the top level"));
+        assert!(out.is_empty());
+    }
+}
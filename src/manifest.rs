@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Error};
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// The manifest as cargo normalised it when publishing
+pub(crate) const NORMALIZED: &str = "Cargo.toml";
+
+/// The author's manifest, as it was before publishing
+pub(crate) const ORIGINAL: &str = "Cargo.toml.orig";
+
+/// Where the normalised manifest is kept when the original is restored
+pub(crate) const KEPT_NORMALIZED: &str = "Cargo.toml.normalized";
+
+/// The archive path an entry is extracted as when restoring the original manifest, if it is one
+/// of the manifests
+pub(crate) fn swap(base: &str, path: &Path) -> Option<PathBuf> {
+    let name = path.strip_prefix(base).ok()?.to_str()?;
+    match name {
+        NORMALIZED => Some(Path::new(base).join(KEPT_NORMALIZED)),
+        ORIGINAL => Some(Path::new(base).join(NORMALIZED)),
+        _ => None,
+    }
+}
+
+/// A unified diff of the changes cargo's normalisation made to the original manifest
+#[culpa::throws]
+#[fn_error_context::context("comparing the manifests of {name} {version}")]
+pub(crate) fn diff(
+    name: &str,
+    version: &str,
+    mut archive: tar::Archive<impl std::io::Read>,
+    limits: crate::unpack::Limits,
+) -> String {
+    let base = format!("{name}-{version}");
+    let (mut normalized, mut original) = (None, None);
+    for entry in limits.entries(&mut archive)? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let manifest = match path.strip_prefix(&base).ok().and_then(|path| path.to_str()) {
+            Some(NORMALIZED) => &mut normalized,
+            Some(ORIGINAL) => &mut original,
+            _ => continue,
+        };
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents)?;
+        *manifest = Some(contents);
+    }
+    let normalized = normalized.ok_or_else(|| anyhow!("{name} {version} has no {NORMALIZED}"))?;
+    let original =
+        original.ok_or_else(|| anyhow!("{name} {version} has no {ORIGINAL} to compare with"))?;
+    similar::TextDiff::from_lines(&original, &normalized)
+        .unified_diff()
+        .header(
+            &format!("{base}/{ORIGINAL}"),
+            &format!("{base}/{NORMALIZED}"),
+        )
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, swap};
+    use crate::unpack::{Limits, Violation};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn swapped_paths() {
+        let swap = |path: &str| swap("foo-0.1.0", Path::new(path));
+        assert_eq!(
+            swap("foo-0.1.0/Cargo.toml"),
+            Some(PathBuf::from("foo-0.1.0/Cargo.toml.normalized"))
+        );
+        assert_eq!(
+            swap("foo-0.1.0/Cargo.toml.orig"),
+            Some(PathBuf::from("foo-0.1.0/Cargo.toml"))
+        );
+        assert_eq!(swap("foo-0.1.0/sub/Cargo.toml"), None);
+        assert_eq!(swap("bar-0.1.0/Cargo.toml"), None);
+        assert_eq!(swap("foo-0.1.0/src/lib.rs"), None);
+    }
+
+    const LIMITS: Limits = Limits {
+        entries: 100,
+        bytes: 1000,
+    };
+
+    fn archive(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn diffs() {
+        let data = archive(&[
+            (
+                "foo-0.1.0/Cargo.toml",
+                "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n",
+            ),
+            (
+                "foo-0.1.0/Cargo.toml.orig",
+                "[package]\nname = \"foo\"\nversion.workspace = true\n",
+            ),
+        ]);
+        assert_eq!(
+            diff("foo", "0.1.0", tar::Archive::new(&data[..]), LIMITS).unwrap(),
+            "--- foo-0.1.0/Cargo.toml.orig\n\
+             +++ foo-0.1.0/Cargo.toml\n\
+             @@ -1,3 +1,3 @@\n \
+             [package]\n \
+             name = \"foo\"\n\
+             -version.workspace = true\n\
+             +version = \"0.1.0\"\n"
+        );
+
+        let data = archive(&[("foo-0.1.0/Cargo.toml", "")]);
+        assert_eq!(
+            diff("foo", "0.1.0", tar::Archive::new(&data[..]), LIMITS)
+                .unwrap_err()
+                .root_cause()
+                .to_string(),
+            "foo 0.1.0 has no Cargo.toml.orig to compare with"
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let data = archive(&[
+            ("foo-0.1.0/Cargo.toml", "[package]\n"),
+            ("foo-0.1.0/Cargo.toml.orig", "[package]\n"),
+        ]);
+        let limits = Limits {
+            entries: 100,
+            bytes: 15,
+        };
+        assert!(matches!(
+            diff("foo", "0.1.0", tar::Archive::new(&data[..]), limits)
+                .unwrap_err()
+                .downcast()
+                .unwrap(),
+            Violation::TooLarge(path, 15) if path == "foo-0.1.0/Cargo.toml.orig"
+        ));
+    }
+}
@@ -1,9 +1,35 @@
-use crate::{crate_name, CrateName};
+use crate::{crate_name, version::VersionReq, CrateName};
+
+/// The git URL cargo uses to identify crates.io in package ids and lockfiles
+pub(crate) const CRATES_IO_GIT_URL: &str = "https://github.com/rust-lang/crates.io-index";
 
 #[derive(Clone, Debug)]
 pub(crate) struct PackageIdSpec {
+    /// Where to fetch the crate from if the spec was fully qualified
+    pub(crate) source: Option<Source>,
     pub(crate) name: CrateName,
-    pub(crate) version_req: Option<semver::VersionReq>,
+    pub(crate) version_req: Option<VersionReq>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Source {
+    /// A registry index url, with crates.io's git url mapped to its sparse equivalent
+    Registry(String),
+    /// A git repository containing the crate somewhere in its workspace
+    Git(GitSource),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct GitSource {
+    pub(crate) url: String,
+    pub(crate) reference: Option<GitReference>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum GitReference {
+    Rev(String),
+    Branch(String),
+    Tag(String),
 }
 
 #[derive(thiserror::Error, Debug, displaydoc::Display)]
@@ -12,6 +38,133 @@ pub(crate) enum ParseError {
     CrateName(#[source] crate_name::ParseError, String),
     /// invalid version request '{1}'
     VersionReq(#[source] semver::Error, String),
+    /// unsupported source kind '{0}', expected one of `registry`, `sparse`, `git`
+    SourceKind(String),
+    /// unsupported git url query '{0}', expected one of `rev`, `branch`, `tag`
+    GitQuery(String),
+    /// could not infer a crate name from the url '{0}'
+    MissingName(String),
+    /// unsupported registry '{0}', only `sparse+` registries are supported
+    UnsupportedRegistry(String),
+}
+
+impl Source {
+    /// The default source, crates.io
+    pub(crate) fn crates_io() -> Self {
+        Source::Registry(crates_index::sparse::URL.to_owned())
+    }
+
+    /// A registry source, which must have a sparse index unless it is crates.io
+    #[culpa::throws(ParseError)]
+    fn registry(url: &str) -> Self {
+        let url = registry_url(url);
+        if !url.starts_with("sparse+") {
+            culpa::throw!(ParseError::UnsupportedRegistry(url));
+        }
+        Source::Registry(url)
+    }
+}
+
+/// The index url of a registry, using crates.io's sparse index in place of its git one
+pub(crate) fn registry_url(url: &str) -> String {
+    if url.trim_end_matches('/') == CRATES_IO_GIT_URL {
+        crates_index::sparse::URL.to_owned()
+    } else {
+        url.to_owned()
+    }
+}
+
+#[culpa::throws(ParseError)]
+fn parse_crate_name(s: &str) -> CrateName {
+    s.parse::<CrateName>()
+        .map_err(|e| ParseError::CrateName(e, s.to_owned()))?
+}
+
+#[culpa::throws(ParseError)]
+fn parse_version_req(s: &str) -> VersionReq {
+    s.parse()
+        .map_err(|e| ParseError::VersionReq(e, s.to_owned()))?
+}
+
+/// Versions in fully qualified specs (as output by `cargo pkgid`) refer to exact versions, which
+/// may be partial, unless an explicit operator is used
+#[culpa::throws(ParseError)]
+fn parse_exact_version(s: &str) -> VersionReq {
+    if s.starts_with(|c: char| c.is_ascii_digit()) {
+        parse_version_req(&format!("={s}"))?
+    } else {
+        parse_version_req(s)?
+    }
+}
+
+#[culpa::throws(ParseError)]
+fn parse_git_url(url: &str) -> GitSource {
+    let Some((url, query)) = url.split_once('?') else {
+        return GitSource {
+            url: url.to_owned(),
+            reference: None,
+        };
+    };
+    let reference = match query.split_once('=') {
+        Some(("rev", rev)) => GitReference::Rev(rev.to_owned()),
+        Some(("branch", branch)) => GitReference::Branch(branch.to_owned()),
+        Some(("tag", tag)) => GitReference::Tag(tag.to_owned()),
+        _ => culpa::throw!(ParseError::GitQuery(query.to_owned())),
+    };
+    GitSource {
+        url: url.to_owned(),
+        reference: Some(reference),
+    }
+}
+
+#[culpa::throws(ParseError)]
+fn parse_url(s: &str) -> PackageIdSpec {
+    let (url, fragment) = match s.split_once('#') {
+        Some((url, fragment)) => (url, Some(fragment)),
+        None => (s, None),
+    };
+
+    let (kind, bare_url) = match url.split_once('+') {
+        Some((kind, rest)) if !kind.contains("://") => (Some(kind), rest),
+        _ => (None, url),
+    };
+    let source = match kind {
+        None => Source::registry(url)?,
+        Some("registry") => Source::registry(bare_url)?,
+        Some("sparse") => Source::registry(url)?,
+        Some("git") => Source::Git(parse_git_url(bare_url)?),
+        Some(kind) => culpa::throw!(ParseError::SourceKind(kind.to_owned())),
+    };
+
+    let url_name = || {
+        let path = bare_url.split('?').next().unwrap_or(bare_url);
+        path.trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .map(|name| name.strip_suffix(".git").unwrap_or(name))
+            .filter(|name| !name.is_empty() && !name.contains("://"))
+            .ok_or_else(|| ParseError::MissingName(url.to_owned()))
+    };
+
+    let (name, version_req) = match fragment {
+        Some(fragment) => match fragment.split_once(['@', ':']) {
+            Some((name, version)) => (parse_crate_name(name)?, Some(parse_exact_version(version)?)),
+            None if fragment.starts_with(|c: char| c.is_alphabetic()) => {
+                (parse_crate_name(fragment)?, None)
+            }
+            None => (
+                parse_crate_name(url_name()?)?,
+                Some(parse_exact_version(fragment)?),
+            ),
+        },
+        None => (parse_crate_name(url_name()?)?, None),
+    };
+
+    PackageIdSpec {
+        source: Some(source),
+        name,
+        version_req,
+    }
 }
 
 impl std::str::FromStr for PackageIdSpec {
@@ -19,21 +172,23 @@ impl std::str::FromStr for PackageIdSpec {
 
     #[culpa::throws(ParseError)]
     fn from_str(s: &str) -> Self {
-        let parse_crate_name = |s: &str| {
-            s.parse::<CrateName>()
-                .map_err(|e| ParseError::CrateName(e, s.to_owned()))
-        };
-        if let Some(i) = s.find('@') {
-            let v = &s[(i + 1)..];
+        if s.contains("://") {
+            parse_url(s)?
+        } else if let Some((name, v)) = s.split_once('@') {
+            Self {
+                source: None,
+                name: parse_crate_name(name)?,
+                version_req: Some(parse_version_req(v)?),
+            }
+        } else if let Some((name, v)) = s.split_once(':') {
             Self {
-                name: parse_crate_name(&s[..i])?,
-                version_req: Some(
-                    v.parse()
-                        .map_err(|e| ParseError::VersionReq(e, v.to_owned()))?,
-                ),
+                source: None,
+                name: parse_crate_name(name)?,
+                version_req: Some(parse_exact_version(v)?),
             }
         } else {
             Self {
+                source: None,
                 name: parse_crate_name(s)?,
                 version_req: None,
             }
@@ -41,17 +196,140 @@ impl std::str::FromStr for PackageIdSpec {
     }
 }
 
+impl std::fmt::Display for Source {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        match self {
+            Source::Registry(url) if url.starts_with("sparse+") => write!(f, "{url}")?,
+            Source::Registry(url) => write!(f, "registry+{url}")?,
+            Source::Git(GitSource { url, reference }) => {
+                write!(f, "git+{url}")?;
+                match reference {
+                    Some(GitReference::Rev(rev)) => write!(f, "?rev={rev}")?,
+                    Some(GitReference::Branch(branch)) => write!(f, "?branch={branch}")?,
+                    Some(GitReference::Tag(tag)) => write!(f, "?tag={tag}")?,
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
 impl std::fmt::Display for PackageIdSpec {
     #[culpa::throws(std::fmt::Error)]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
-        if let PackageIdSpec {
-            name,
-            version_req: Some(version_req),
-        } = self
-        {
-            f.pad(&format!("{name}@{version_req}"))?;
-        } else {
-            write!(f, "{}", self.name)?;
+        let mut s = String::new();
+        if let Some(source) = &self.source {
+            s.push_str(&format!("{source}#"));
+        }
+        s.push_str(&self.name.0);
+        if let Some(version_req) = &self.version_req {
+            s.push_str(&format!("@{version_req}"));
+        }
+        f.pad(&s)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GitReference, GitSource, PackageIdSpec, ParseError, Source};
+
+    fn parse(s: &str) -> PackageIdSpec {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn bare_specs() {
+        let spec = parse("serde");
+        assert_eq!(spec.source, None);
+        assert_eq!(spec.name.0, "serde");
+        assert!(spec.version_req.is_none());
+
+        assert_eq!(parse("serde@1.0").to_string(), "serde@^1.0");
+        assert_eq!(parse("serde:1.0").to_string(), "serde@=1.0");
+    }
+
+    #[test]
+    fn registry_urls() {
+        let spec = parse("registry+https://github.com/rust-lang/crates.io-index#serde@1.0.188");
+        assert_eq!(spec.source, Some(Source::crates_io()));
+        assert_eq!(spec.name.0, "serde");
+        assert_eq!(spec.version_req.unwrap().to_string(), "=1.0.188");
+
+        let spec = parse("sparse+https://example.com/index/#foo");
+        assert_eq!(
+            spec.source,
+            Some(Source::Registry(
+                "sparse+https://example.com/index/".to_owned()
+            ))
+        );
+        assert_eq!(spec.to_string(), "sparse+https://example.com/index/#foo");
+
+        let spec = parse("https://github.com/rust-lang/crates.io-index#serde:1.0");
+        assert_eq!(
+            spec.to_string(),
+            format!("{}#serde@=1.0", Source::crates_io())
+        );
+    }
+
+    #[test]
+    fn git_urls() {
+        let spec =
+            parse("git+https://github.com/serde-rs/serde.git?tag=v1.0.188#serde_derive@1.0.188");
+        assert_eq!(
+            spec.source,
+            Some(Source::Git(GitSource {
+                url: "https://github.com/serde-rs/serde.git".to_owned(),
+                reference: Some(GitReference::Tag("v1.0.188".to_owned())),
+            }))
+        );
+        assert_eq!(spec.name.0, "serde_derive");
+
+        let spec = parse("git+https://github.com/rust-lang/regex.git?rev=abc123");
+        assert_eq!(spec.name.0, "regex");
+        assert_eq!(
+            spec.to_string(),
+            "git+https://github.com/rust-lang/regex.git?rev=abc123#regex"
+        );
+
+        let spec = parse("git+https://github.com/rust-lang/regex#1.9");
+        assert_eq!(
+            spec.to_string(),
+            "git+https://github.com/rust-lang/regex#regex@=1.9"
+        );
+    }
+
+    #[test]
+    fn invalid_urls() {
+        assert!(matches!(
+            "svn+https://example.com/foo".parse::<PackageIdSpec>(),
+            Err(ParseError::SourceKind(kind)) if kind == "svn"
+        ));
+        assert!(matches!(
+            "git+https://example.com/foo?commit=abc".parse::<PackageIdSpec>(),
+            Err(ParseError::GitQuery(query)) if query == "commit=abc"
+        ));
+        assert!(matches!(
+            "git+https://example.com/.git#1.0".parse::<PackageIdSpec>(),
+            Err(ParseError::MissingName(_))
+        ));
+    }
+
+    #[test]
+    fn unsupported_registries() {
+        for spec in [
+            "registry+https://example.com/index#foo",
+            "https://example.com/index#foo@1.0",
+        ] {
+            let err = spec.parse::<PackageIdSpec>().unwrap_err();
+            assert!(
+                matches!(&err, ParseError::UnsupportedRegistry(url) if url == "https://example.com/index"),
+                "{err:?}"
+            );
+            assert_eq!(
+                err.to_string(),
+                "unsupported registry 'https://example.com/index', only `sparse+` registries are supported"
+            );
         }
     }
 }
@@ -0,0 +1,247 @@
+use anyhow::{anyhow, Context, Error};
+use std::path::{Path, PathBuf};
+
+/// The per-user directory playgrounds are kept in, which unlike the shared temporary directory
+/// other users cannot plant crates in
+#[culpa::throws]
+fn base_dir() -> PathBuf {
+    dirs::cache_dir()
+        .context("could not find the user's cache directory")?
+        .join("cargo-dl")
+}
+
+/// Where crates are extracted to for use by playgrounds
+#[culpa::throws]
+pub(crate) fn crate_dir(name: &str, version: &str) -> PathBuf {
+    base_dir()?.join("crates").join(format!("{name}-{version}"))
+}
+
+/// The default location of the playground for a crate
+#[culpa::throws]
+pub(crate) fn project_dir(name: &str, version: &str) -> PathBuf {
+    base_dir()?
+        .join("playground")
+        .join(format!("{name}-{version}"))
+}
+
+/// The name the crate's library is imported as
+#[culpa::throws]
+fn lib_name(manifest: &toml::Table, krate: &Path, name: &str) -> String {
+    let lib = manifest.get("lib");
+    if lib.is_none() && !krate.join("src/lib.rs").is_file() {
+        culpa::throw!(anyhow!("{name} has no library to use from a playground"));
+    }
+    lib.and_then(|lib| lib.get("name"))
+        .and_then(|name| name.as_str())
+        .unwrap_or(name)
+        .replace('-', "_")
+}
+
+/// Copies the crate's auto-discovered examples into the playground as binaries
+#[culpa::throws]
+fn copy_examples(krate: &Path, project: &Path) -> Vec<String> {
+    let mut bins = Vec::new();
+    let examples = krate.join("examples");
+    if !examples.is_dir() {
+        return bins;
+    }
+    let bin_dir = project.join("src").join("bin");
+    for entry in std::fs::read_dir(&examples)? {
+        let path = entry?.path();
+        let Some(stem) = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+        else {
+            continue;
+        };
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "rs") {
+            std::fs::create_dir_all(&bin_dir)?;
+            std::fs::copy(&path, bin_dir.join(format!("{stem}.rs")))?;
+        } else if path.join("main.rs").is_file() {
+            copy_dir(&path, &bin_dir.join(&stem))?;
+        } else {
+            continue;
+        }
+        bins.push(stem);
+    }
+    bins.sort();
+    bins
+}
+
+#[culpa::throws]
+fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()))?;
+        } else {
+            std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+}
+
+/// The playground's manifest
+#[derive(serde::Serialize)]
+struct Manifest {
+    package: Package,
+    /// Always empty, to keep the playground out of any enclosing workspace
+    workspace: toml::Table,
+    dependencies: toml::Table,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Package {
+    name: String,
+    version: &'static str,
+    edition: &'static str,
+    publish: bool,
+    default_run: String,
+}
+
+/// The contents of the playground's Cargo.toml, a binary package depending on `dependencies`
+#[culpa::throws(toml::ser::Error)]
+fn project_manifest(name: &str, dependencies: toml::Table) -> String {
+    let package = format!("{name}-playground");
+    toml::to_string(&Manifest {
+        package: Package {
+            name: package.clone(),
+            version: "0.0.0",
+            edition: "2021",
+            publish: false,
+            default_run: package,
+        },
+        workspace: toml::Table::new(),
+        dependencies,
+    })?
+}
+
+/// Creates a scratch binary project depending on the extracted crate through a path dependency,
+/// optionally with the crate's examples as binaries, returning the names of the example binaries
+#[culpa::throws]
+#[fn_error_context::context("creating playground for {name} {version}")]
+pub(crate) fn create(
+    krate: &Path,
+    name: &str,
+    version: &str,
+    project: &Path,
+    examples: bool,
+) -> Vec<String> {
+    let manifest_path = krate.join("Cargo.toml");
+    let manifest: toml::Table = toml::from_str(
+        &std::fs::read_to_string(&manifest_path)
+            .with_context(|| format!("reading {}", manifest_path.display()))?,
+    )?;
+    let lib = lib_name(&manifest, krate, name)?;
+
+    std::fs::create_dir_all(project.join("src"))?;
+    let bins = if examples {
+        copy_examples(krate, project)?
+    } else {
+        Vec::new()
+    };
+
+    let mut dependencies = toml::Table::new();
+    let mut dependency = toml::Table::new();
+    dependency.insert("path".into(), krate.display().to_string().into());
+    dependencies.insert(name.into(), dependency.into());
+    if !bins.is_empty() {
+        // Examples are built with the crate's dev-dependencies available
+        let dev_dependencies = manifest
+            .get("dev-dependencies")
+            .and_then(|deps| deps.as_table())
+            .into_iter()
+            .flatten();
+        for (key, value) in dev_dependencies {
+            dependencies
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+    }
+
+    std::fs::write(
+        project.join("Cargo.toml"),
+        project_manifest(name, dependencies)?,
+    )?;
+
+    std::fs::write(
+        project.join("src").join("main.rs"),
+        format!("#[allow(unused_imports)]\nuse {lib}::*;\n\nfn main() {{\n}}\n"),
+    )?;
+
+    bins
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create, project_manifest};
+    use std::path::Path;
+
+    fn read(path: &Path) -> toml::Table {
+        toml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn manifests() {
+        let dependencies = toml::Table::from_iter([(
+            "foo".to_owned(),
+            toml::Value::from(toml::Table::from_iter([(
+                "path".to_owned(),
+                toml::Value::from(r#"C:\crates\"foo"-1.0.0"#),
+            )])),
+        )]);
+        let manifest = project_manifest("foo", dependencies).unwrap();
+        assert!(manifest.starts_with("[package]\nname = \"foo-playground\"\n"));
+        let manifest: toml::Table = toml::from_str(&manifest).unwrap();
+        assert_eq!(manifest["package"]["name"].as_str(), Some("foo-playground"));
+        assert_eq!(
+            manifest["package"]["default-run"].as_str(),
+            Some("foo-playground")
+        );
+        assert_eq!(manifest["package"]["publish"].as_bool(), Some(false));
+        assert_eq!(manifest["workspace"].as_table(), Some(&toml::Table::new()));
+        assert_eq!(
+            manifest["dependencies"]["foo"]["path"].as_str(),
+            Some(r#"C:\crates\"foo"-1.0.0"#)
+        );
+    }
+
+    #[test]
+    fn playgrounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let krate = dir.path().join(r#"my "crate"-1.0.0"#);
+        std::fs::create_dir_all(krate.join("src")).unwrap();
+        std::fs::create_dir_all(krate.join("examples")).unwrap();
+        std::fs::write(krate.join("src/lib.rs"), "").unwrap();
+        std::fs::write(krate.join("examples/demo.rs"), "fn main() {}").unwrap();
+        std::fs::write(
+            krate.join("Cargo.toml"),
+            "[package]\nname = \"my-crate\"\n\n[dev-dependencies]\nrand = \"0.8\"\n",
+        )
+        .unwrap();
+        let project = dir.path().join("playground");
+
+        assert!(create(&krate, "my-crate", "1.0.0", &project, false)
+            .unwrap()
+            .is_empty());
+        let manifest = read(&project.join("Cargo.toml"));
+        assert_eq!(
+            manifest["dependencies"]["my-crate"]["path"].as_str(),
+            krate.to_str()
+        );
+        assert!(manifest["dependencies"].get("rand").is_none());
+        assert_eq!(
+            std::fs::read_to_string(project.join("src/main.rs")).unwrap(),
+            "#[allow(unused_imports)]\nuse my_crate::*;\n\nfn main() {\n}\n"
+        );
+
+        assert_eq!(
+            create(&krate, "my-crate", "1.0.0", &project, true).unwrap(),
+            ["demo"]
+        );
+        let manifest = read(&project.join("Cargo.toml"));
+        assert_eq!(manifest["dependencies"]["rand"].as_str(), Some("0.8"));
+        assert!(project.join("src/bin/demo.rs").is_file());
+    }
+}
@@ -0,0 +1,194 @@
+use anyhow::{anyhow, Error};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+/// Names Windows reserves for devices, whatever extension they are given
+const RESERVED: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Characters that are not allowed in names on Windows, along with control characters
+const INVALID: &[char] = &['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+/// The longest path Windows supports by default
+const MAX_PATH: usize = 260;
+
+/// The longest name most filesystems support
+const MAX_NAME: usize = 255;
+
+/// Collects the paths being extracted that would not work on case-insensitive or Windows
+/// filesystems
+#[derive(Debug, Default)]
+pub(crate) struct Check {
+    /// Each path seen so far, keyed by its lowercased form
+    seen: HashMap<String, PathBuf>,
+    reported: HashSet<String>,
+    problems: Vec<String>,
+}
+
+impl Check {
+    /// Checks the path of an entry relative to the output directory, which is named `base`
+    pub(crate) fn entry(&mut self, base: &str, relative: &Path) {
+        let mut prefix = PathBuf::new();
+        for component in relative.components() {
+            prefix.push(component);
+            let key = prefix.to_string_lossy().to_lowercase();
+            match self.seen.entry(key.clone()) {
+                Entry::Occupied(seen) => {
+                    if seen.get() != &prefix {
+                        if self.reported.insert(key) {
+                            self.problems.push(format!(
+                                "{} and {} differ only by case",
+                                seen.get().display(),
+                                prefix.display()
+                            ));
+                        }
+                        // Everything inside collides too
+                        break;
+                    }
+                }
+                Entry::Vacant(seen) => {
+                    seen.insert(prefix.clone());
+                    self.name(&prefix, &component.as_os_str().to_string_lossy());
+                }
+            }
+        }
+
+        let length = base.chars().count() + 1 + relative.to_string_lossy().chars().count();
+        if length > MAX_PATH {
+            self.problems.push(format!(
+                "{} is {length} characters long including {base}/, over the Windows limit of {MAX_PATH}",
+                relative.display()
+            ));
+        }
+    }
+
+    fn name(&mut self, path: &Path, name: &str) {
+        let display = path.display();
+        let stem = name.split('.').next().unwrap_or(name).trim_end();
+        if RESERVED.contains(&stem.to_lowercase().as_str()) {
+            self.problems.push(format!(
+                "{display} uses the name {stem}, which is reserved on Windows"
+            ));
+        }
+        if name.ends_with('.') || name.ends_with(' ') {
+            self.problems.push(format!(
+                "{display} ends with a dot or space, which Windows removes"
+            ));
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|&c| INVALID.contains(&c) || c.is_control())
+        {
+            self.problems.push(format!(
+                "{display} contains {c:?}, which is not allowed on Windows"
+            ));
+        }
+        if name.len() > MAX_NAME {
+            self.problems
+                .push(format!("{display} has a name longer than {MAX_NAME} bytes"));
+        }
+    }
+
+    /// Reports any problems found, as an error if they are denied or otherwise as a warning
+    #[culpa::throws]
+    pub(crate) fn finish(self, name: &str, version: &str, deny: bool) {
+        if self.problems.is_empty() {
+            return;
+        }
+        let mut message =
+            format!("{name} {version} is not portable to case-insensitive or Windows filesystems:");
+        for problem in &self.problems {
+            message.push_str("\n  ");
+            message.push_str(problem);
+        }
+        if deny {
+            culpa::throw!(anyhow!(message));
+        }
+        tracing::warn!("{message}\nuse --deny-unportable to fail instead");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Check;
+    use std::path::Path;
+
+    fn problems(paths: &[&str]) -> Vec<String> {
+        let mut check = Check::default();
+        for path in paths {
+            check.entry("foo-0.1.0", Path::new(path));
+        }
+        check.problems
+    }
+
+    #[test]
+    fn portable_paths() {
+        assert!(problems(&[
+            "src",
+            "src/lib.rs",
+            "src/lib.rs",
+            "Cargo.toml",
+            "console.rs"
+        ])
+        .is_empty());
+    }
+
+    #[test]
+    fn case_collisions() {
+        assert_eq!(
+            problems(&[
+                "src/lib.rs",
+                "Src/main.rs",
+                "Src/other.rs",
+                "README",
+                "readme"
+            ]),
+            [
+                "src and Src differ only by case",
+                "README and readme differ only by case"
+            ]
+        );
+    }
+
+    #[test]
+    fn windows_names() {
+        assert_eq!(
+            problems(&["src/aux.rs", "Con", "COM1 .txt", "trail.", "a:b?"]),
+            [
+                "src/aux.rs uses the name aux, which is reserved on Windows",
+                "Con uses the name Con, which is reserved on Windows",
+                "COM1 .txt uses the name COM1, which is reserved on Windows",
+                "trail. ends with a dot or space, which Windows removes",
+                "a:b? contains ':', which is not allowed on Windows",
+            ]
+        );
+    }
+
+    #[test]
+    fn long_paths() {
+        let name = "n".repeat(256);
+        assert_eq!(
+            problems(&[&name]),
+            [
+                format!("{name} has a name longer than 255 bytes"),
+                format!("{name} is 266 characters long including foo-0.1.0/, over the Windows limit of 260"),
+            ]
+        );
+    }
+
+    #[test]
+    fn denied() {
+        let mut check = Check::default();
+        check.entry("foo-0.1.0", Path::new("aux.rs"));
+        assert_eq!(
+            check.finish("foo", "0.1.0", true).unwrap_err().to_string(),
+            "foo 0.1.0 is not portable to case-insensitive or Windows filesystems:\n  \
+             aux.rs uses the name aux, which is reserved on Windows"
+        );
+        Check::default().finish("foo", "0.1.0", true).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::{Context, Error};
+use std::path::{Path, PathBuf};
+
+#[derive(serde::Serialize)]
+struct Config<'a> {
+    dl: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api: Option<&'a str>,
+}
+
+/// The path of a crate's file within an index, sharded by prefix the same as cargo does
+pub(crate) fn index_path(name: &str) -> PathBuf {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => ["1", &name].iter().collect(),
+        2 => ["2", &name].iter().collect(),
+        3 => ["3", &name[..1], &name].iter().collect(),
+        _ => [&name[..2], &name[2..4], &name].iter().collect(),
+    }
+}
+
+/// The index entry line of the version, copied as the registry served it so that fields unknown
+/// to `crates_index` are kept
+#[culpa::throws]
+fn entry_line(registry: &str, version: &crates_index::Version) -> String {
+    match crate::index_cache::line(registry, version.name(), version.version())? {
+        Some(line) => line,
+        None => {
+            tracing::warn!(
+                "the index entry of {} {} was not fetched, fields unknown to cargo-dl will be missing from the copy",
+                version.name(),
+                version.version()
+            );
+            serde_json::to_string(version)?
+        }
+    }
+}
+
+/// Adds the version's entry from the registry to the index file of its crate, replacing any
+/// existing entry for the same version
+#[culpa::throws]
+#[fn_error_context::context("adding {} {} to the index in {}", version.name(), version.version(), index.display())]
+pub(crate) fn add_entry(index: &Path, registry: &str, version: &crates_index::Version) {
+    let path = index.join(index_path(version.name()));
+    let mut lines = Vec::new();
+    if path.exists() {
+        let existing = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        for line in existing.lines().filter(|line| !line.trim().is_empty()) {
+            let entry: crates_index::Version = serde_json::from_str(line)
+                .with_context(|| format!("parsing entry in {}", path.display()))?;
+            if entry.version() != version.version() {
+                lines.push(line.to_owned());
+            }
+        }
+    }
+    lines.push(entry_line(registry, version)?);
+    crate::atomic::write(&path, lines.join("\n") + "\n")?;
+}
+
+/// The config of a mirror served from `url`, by default with the crate files also served from it
+pub(crate) fn mirror_config(
+    url: &str,
+    dl: Option<String>,
+    api: Option<String>,
+) -> crates_index::IndexConfig {
+    let url = url.trim_end_matches('/');
+    crates_index::IndexConfig {
+        dl: dl.unwrap_or_else(|| format!("{url}/crates/{{crate}}/{{crate}}-{{version}}.crate")),
+        api,
+    }
+}
+
+/// Where a crate file downloaded from `download` is written within a mirror served from `url`,
+/// unless it is downloaded from elsewhere
+pub(crate) fn mirror_path(url: &str, download: &str) -> Option<PathBuf> {
+    let base = format!("{}/", url.trim_end_matches('/'));
+    download.strip_prefix(&base).map(PathBuf::from)
+}
+
+/// Writes the `config.json` of a sparse index
+#[culpa::throws]
+#[fn_error_context::context("writing config.json to {}", dir.display())]
+pub(crate) fn write_config(dir: &Path, config: &crates_index::IndexConfig) {
+    let config = Config {
+        dl: &config.dl,
+        api: config.api.as_deref(),
+    };
+    crate::atomic::write(
+        &dir.join("config.json"),
+        serde_json::to_string_pretty(&config)? + "\n",
+    )?;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_entry, index_path, mirror_config, mirror_path, write_config};
+    use std::path::Path;
+
+    const REGISTRY: &str = "sparse+https://registry.example/";
+
+    /// An index entry line with fields `crates_index` does not keep
+    fn line(name: &str, vers: &str) -> String {
+        format!(
+            r#"{{"name":"{name}","vers":"{vers}","deps":[],"cksum":"0000000000000000000000000000000000000000000000000000000000000000","features":{{}},"features2":{{"json":["dep:serde_json"]}},"yanked":false,"v":2}}"#
+        )
+    }
+
+    fn version(line: &str) -> crates_index::Version {
+        crates_index::Crate::from_slice(line.as_bytes())
+            .unwrap()
+            .versions()[0]
+            .clone()
+    }
+
+    #[test]
+    fn index_paths() {
+        for (name, path) in [
+            ("a", "1/a"),
+            ("ab", "2/ab"),
+            ("abc", "3/a/abc"),
+            ("Serde_JSON", "se/rd/serde_json"),
+        ] {
+            assert_eq!(index_path(name), Path::new(path));
+        }
+    }
+
+    #[test]
+    fn local_registry_entries() {
+        let (first, second) = (line("foo", "0.1.0"), line("foo", "0.2.0"));
+        crate::index_cache::record(REGISTRY, "foo", format!("{first}\n{second}\n").as_bytes());
+
+        let dir = tempfile::tempdir().unwrap();
+        let index = dir.path().join("index");
+        add_entry(&index, REGISTRY, &version(&first)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(index.join("3/f/foo")).unwrap(),
+            format!("{first}\n")
+        );
+
+        // A second run merges into the existing index, replacing entries for the same version
+        add_entry(&index, REGISTRY, &version(&second)).unwrap();
+        add_entry(&index, REGISTRY, &version(&first)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(index.join("3/f/foo")).unwrap(),
+            format!("{second}\n{first}\n")
+        );
+    }
+
+    #[test]
+    fn mirror_layout() {
+        let entry = line("foo", "1.0.0");
+        crate::index_cache::record(REGISTRY, "foo", entry.as_bytes());
+        let version = version(&entry);
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = mirror_config("https://mirror.example/", None, None);
+        write_config(dir.path(), &config).unwrap();
+        add_entry(dir.path(), REGISTRY, &version).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join("config.json")).unwrap())
+                .unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!({
+                "dl": "https://mirror.example/crates/{crate}/{crate}-{version}.crate",
+            })
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("3/f/foo")).unwrap(),
+            format!("{entry}\n")
+        );
+        let download = version.download_url(&config).unwrap();
+        assert_eq!(
+            mirror_path("https://mirror.example", &download),
+            Some(Path::new("crates/foo/foo-1.0.0.crate").to_owned())
+        );
+
+        let config = mirror_config(
+            "https://mirror.example",
+            Some("https://static.example/{crate}/{version}".to_owned()),
+            Some("https://api.example".to_owned()),
+        );
+        write_config(dir.path(), &config).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join("config.json")).unwrap())
+                .unwrap();
+        assert_eq!(written["api"], "https://api.example");
+        let download = version.download_url(&config).unwrap();
+        assert_eq!(mirror_path("https://mirror.example", &download), None);
+    }
+}
@@ -0,0 +1,93 @@
+use crate::package_id_spec::PackageIdSpec;
+use anyhow::{anyhow, Context, Error};
+use std::{io::BufRead, path::Path};
+
+/// Reads one spec per line from the file, or stdin if the path is `-`.
+///
+/// Blank lines and `#` comments (either a whole line, or after whitespace following the spec) are
+/// ignored, and repeated specs are only returned once.
+#[culpa::throws]
+#[fn_error_context::context("reading specs from {}", path.display())]
+pub(crate) fn read(path: &Path) -> Vec<PackageIdSpec> {
+    let reader: Box<dyn BufRead> = if path == Path::new("-") {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(std::io::BufReader::new(std::fs::File::open(path)?))
+    };
+    parse(reader)?
+}
+
+#[culpa::throws]
+fn parse(reader: impl BufRead) -> Vec<PackageIdSpec> {
+    let mut specs = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let number = number + 1;
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let Some(spec) = words.next().filter(|word| !word.starts_with('#')) else {
+            continue;
+        };
+        if let Some(word) = words.next().filter(|word| !word.starts_with('#')) {
+            culpa::throw!(anyhow!("line {number}: unexpected {word:?} after spec"));
+        }
+        let spec: PackageIdSpec = spec
+            .parse()
+            .with_context(|| format!("line {number}: invalid spec {spec:?}"))?;
+        push_unique(&mut specs, spec);
+    }
+    specs
+}
+
+/// Adds the spec unless an identical one was already added
+pub(crate) fn push_unique(specs: &mut Vec<PackageIdSpec>, spec: PackageIdSpec) {
+    if !specs
+        .iter()
+        .any(|existing| existing.to_string() == spec.to_string())
+    {
+        specs.push(spec);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    fn specs(s: &str) -> Vec<String> {
+        Vec::from_iter(
+            parse(s.as_bytes())
+                .unwrap()
+                .into_iter()
+                .map(|spec| spec.to_string()),
+        )
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        assert_eq!(
+            specs("# dependencies\n\nserde@1  # for derive\n  \n\tregex\n#anyhow\n"),
+            ["serde@^1", "regex"]
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        assert_eq!(
+            specs("serde@1\nregex\nserde@^1\nserde@1.0\nregex\n"),
+            ["serde@^1", "regex", "serde@^1.0"]
+        );
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = |s: &str| format!("{:#}", parse(s.as_bytes()).unwrap_err());
+        assert_eq!(
+            error("serde\n\nnot a spec\n"),
+            "line 3: unexpected \"a\" after spec"
+        );
+        assert!(
+            error("# comment\nserde@>>1\n").starts_with("line 2: invalid spec \"serde@>>1\": "),
+            "{}",
+            error("# comment\nserde@>>1\n")
+        );
+    }
+}
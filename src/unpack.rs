@@ -1,31 +1,1200 @@
 use anyhow::{anyhow, Context, Error};
-use std::path::{Component, Path};
+use std::path::{Component, Path, PathBuf};
+
+/// Limits on what an archive may unpack to, protecting against archive bombs
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Limits {
+    pub(crate) entries: u64,
+    pub(crate) bytes: u64,
+}
+
+impl Limits {
+    /// Iterates over the archive's entries, failing at the first one beyond the limits
+    #[culpa::throws]
+    pub(crate) fn entries<R: std::io::Read>(self, archive: &mut tar::Archive<R>) -> Entries<'_, R> {
+        Entries {
+            entries: archive.entries()?,
+            limits: self,
+            count: 0,
+            bytes: 0,
+        }
+    }
+}
+
+/// The entries of an archive, counted against the limits as they are read
+pub(crate) struct Entries<'a, R: std::io::Read> {
+    entries: tar::Entries<'a, R>,
+    limits: Limits,
+    count: u64,
+    bytes: u64,
+}
+
+impl<'a, R: std::io::Read> Entries<'a, R> {
+    #[culpa::throws]
+    fn count(&mut self, entry: &tar::Entry<'a, R>) {
+        self.count += 1;
+        if self.count > self.limits.entries {
+            let display = entry.path()?.display().to_string();
+            culpa::throw!(Violation::TooManyEntries(display, self.limits.entries));
+        }
+        self.bytes += entry.header().size()?;
+        if self.bytes > self.limits.bytes {
+            let display = entry.path()?.display().to_string();
+            culpa::throw!(Violation::TooLarge(display, self.limits.bytes));
+        }
+    }
+}
+
+impl<'a, R: std::io::Read> Iterator for Entries<'a, R> {
+    type Item = Result<tar::Entry<'a, R>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        crate::interrupt::checkpoint();
+        let entry = match self.entries.next()? {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e.into())),
+        };
+        Some(self.count(&entry).map(|()| entry))
+    }
+}
+
+/// How paths in the archive map to paths in the output directory
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Layout {
+    /// Everything must be inside the `<name>-<version>/` directory that is stripped, as cargo
+    /// requires
+    Crate,
+    /// Strip this many leading components, whatever their names
+    Strip(usize),
+}
+
+/// Permissions given to extracted files and directories instead of those in the archive
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Mode {
+    file: u32,
+    /// For directories and files that are executable in the archive
+    executable: u32,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = anyhow::Error;
+
+    #[culpa::throws]
+    fn from_str(mode: &str) -> Self {
+        let parse = |mode: &str| -> Result<u32, Error> {
+            let parsed = u32::from_str_radix(mode, 8)
+                .with_context(|| format!("{mode} is not an octal mode"))?;
+            if parsed > 0o777 {
+                culpa::throw!(anyhow!(
+                    "{mode} is not a permission mode, it should be at most 0777"
+                ));
+            }
+            Ok(parsed)
+        };
+        match mode.split_once('/') {
+            Some((file, executable)) => Self {
+                file: parse(file)?,
+                executable: parse(executable)?,
+            },
+            None => {
+                let file = parse(mode)?;
+                // Executable by whoever can read it
+                Self {
+                    file,
+                    executable: file | (file & 0o444) >> 2,
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04o}/{:04o}", self.file, self.executable)
+    }
+}
+
+/// Which modification time extracted files are given
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Mtime {
+    /// The time recorded in the archive
+    Archive,
+    /// The time the file is extracted
+    Now,
+}
+
+impl std::fmt::Display for Mtime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Mtime::Archive => "archive",
+            Mtime::Now => "now",
+        })
+    }
+}
+
+/// Controls how an archive is unpacked
+#[derive(Clone, Debug)]
+pub(crate) struct Options {
+    pub(crate) layout: Layout,
+    pub(crate) limits: Limits,
+    /// Replaces the permissions from the archive, if set
+    pub(crate) mode: Option<Mode>,
+    pub(crate) mtime: Mtime,
+    /// Fail instead of warning if any paths would not work on case-insensitive or Windows
+    /// filesystems
+    pub(crate) deny_unportable: bool,
+    /// Extract the author's original manifest as `Cargo.toml`, keeping cargo's normalised one
+    /// alongside
+    pub(crate) original_manifest: bool,
+    /// Only unpack entries matching one of these, if there are any
+    pub(crate) include: Vec<globset::Glob>,
+    /// Skip entries matching any of these
+    pub(crate) exclude: Vec<globset::Glob>,
+}
+
+/// Parses a glob matched against paths relative to the crate root, where `*` does not match `/`
+pub(crate) fn parse_glob(glob: &str) -> Result<globset::Glob, globset::Error> {
+    globset::GlobBuilder::new(glob)
+        .literal_separator(true)
+        .build()
+}
+
+#[culpa::throws]
+fn glob_set(globs: &[globset::Glob]) -> globset::GlobSet {
+    let mut builder = globset::GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(glob.clone());
+    }
+    builder.build()?
+}
+
+impl Layout {
+    /// The path within the output directory of an archive path, `None` for a directory that is
+    /// stripped entirely, or why it is misplaced
+    fn map(&self, base: &str, path: &Path, is_dir: bool) -> Result<Option<PathBuf>, String> {
+        let mapped = match *self {
+            Layout::Crate => match path.strip_prefix(base) {
+                Ok(mapped) => mapped,
+                Err(_) => {
+                    let mut components = path.components();
+                    let first = components.next().map(|c| c.as_os_str().to_string_lossy());
+                    return Err(match first {
+                        Some(first) if components.next().is_some() || is_dir => {
+                            format!("is under {first}/ instead of {base}/")
+                        }
+                        _ => format!("is at the top level instead of under {base}/"),
+                    });
+                }
+            },
+            Layout::Strip(count) => {
+                let mut components = path.components();
+                let total = components.clone().count();
+                if total <= count && !is_dir {
+                    return Err(format!(
+                        "has too few path components ({total}) to strip {count}"
+                    ));
+                }
+                components.by_ref().take(count).for_each(drop);
+                components.as_path()
+            }
+        };
+        if mapped.as_os_str().is_empty() {
+            if !is_dir {
+                return Err("would replace the output directory itself".to_owned());
+            }
+            return Ok(None);
+        }
+        Ok(Some(mapped.to_owned()))
+    }
+}
+
+/// Checks that every entry in the archive maps into the output directory, explaining which are
+/// misplaced and why
+#[culpa::throws]
+pub(crate) fn validate(
+    name: &str,
+    version: &str,
+    mut archive: tar::Archive<impl std::io::Read>,
+    layout: &Layout,
+    limits: Limits,
+) {
+    let base = format!("{name}-{version}");
+    let mut misplaced = Vec::new();
+    for entry in limits.entries(&mut archive)? {
+        let entry = entry?;
+        let path = entry.path()?;
+        let is_dir = entry.header().entry_type().is_dir();
+        if let Err(reason) = layout.map(&base, &path, is_dir) {
+            misplaced.push(format!("{} {reason}", path.display()));
+        }
+    }
+    if !misplaced.is_empty() {
+        const SHOWN: usize = 10;
+        let mut message = match misplaced.len() {
+            1 => "1 entry in the archive is misplaced:".to_owned(),
+            count => format!("{count} entries in the archive are misplaced:"),
+        };
+        for entry in misplaced.iter().take(SHOWN) {
+            message.push_str("\n  ");
+            message.push_str(entry);
+        }
+        if misplaced.len() > SHOWN {
+            message.push_str(&format!("\n  and {} more", misplaced.len() - SHOWN));
+        }
+        if let Layout::Crate = layout {
+            message.push_str("\nuse --strip-components or --keep-top-level to extract it anyway");
+        }
+        culpa::throw!(anyhow!(message));
+    }
+}
+
+/// Streams the files in the archive matching any of the globs to `out` without writing anything
+/// to disk, returning how many were printed and failing if a glob matched nothing
+#[culpa::throws]
+pub(crate) fn cat(
+    name: &str,
+    version: &str,
+    mut archive: tar::Archive<impl std::io::Read>,
+    layout: &Layout,
+    limits: Limits,
+    globs: &[globset::Glob],
+    out: &mut impl std::io::Write,
+) -> usize {
+    let base = format!("{name}-{version}");
+    let set = glob_set(globs)?;
+    let mut matched = vec![false; globs.len()];
+    let mut printed = 0;
+    for entry in limits.entries(&mut archive)? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let entry_type = entry.header().entry_type();
+        let Ok(Some(relative)) = layout.map(&base, &path, entry_type.is_dir()) else {
+            continue;
+        };
+        let matches = set.matches(&relative);
+        if matches.is_empty() {
+            continue;
+        }
+        for &index in &matches {
+            matched[index] = true;
+        }
+        if entry_type != tar::EntryType::Regular {
+            tracing::info!("not printing {}, it is not a file", relative.display());
+            continue;
+        }
+        tracing::debug!("printing {}", relative.display());
+        match std::io::copy(&mut entry, out) {
+            // The reader has gone away, e.g. when piped into `head`
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return printed,
+            result => result.with_context(|| format!("printing {}", relative.display()))?,
+        };
+        printed += 1;
+    }
+    out.flush()?;
+
+    if let Some((glob, _)) = globs.iter().zip(matched).find(|(_, matched)| !matched) {
+        culpa::throw!(anyhow!("--cat {glob} matched nothing in {name} {version}"));
+    }
+    printed
+}
+
+/// An archive entry that the extraction policy refuses to unpack
+#[derive(thiserror::Error, Debug, displaydoc::Display)]
+pub(crate) enum Violation {
+    /// a file in the archive ({0}) contains a .. or root segment
+    Path(String),
+
+    /// {0} links to {1}, which is outside the output directory
+    EscapingLink(String, String),
+
+    /// {0} {1}
+    Misplaced(String, String),
+
+    /// {0} is inside {1}, which is a link
+    ThroughLink(String, String),
+
+    /// {0} links through {1}, which is a link
+    LinkThroughLink(String, String),
+
+    /// {0} is a hard link to {1}, which has not been unpacked
+    MissingLinkTarget(String, String),
+
+    /// {0} is a {1:?} entry, only files, directories and links are allowed
+    EntryType(String, tar::EntryType),
+
+    /// {0} exceeds the limit of {1} entries
+    TooManyEntries(String, u64),
+
+    /// {0} exceeds the limit of {1} unpacked bytes
+    TooLarge(String, u64),
+}
+
+/// Calls `f` with every file and directory in the tree, not following symlinks
+#[culpa::throws]
+fn walk(path: &Path, f: &mut impl FnMut(&Path, &std::fs::Metadata) -> Result<(), Error>) {
+    let metadata = path.symlink_metadata()?;
+    if metadata.is_symlink() {
+        return;
+    }
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            walk(&entry?.path(), f)?;
+        }
+    }
+    f(path, &metadata)?;
+}
+
+/// Replaces the permissions of everything in the tree, keeping files that are executable as
+/// executable
+#[culpa::throws]
+#[fn_error_context::context("setting permissions in {}", dir.display())]
+fn set_modes(dir: &Path, mode: Mode) {
+    #[cfg(unix)]
+    walk(dir, &mut |path, metadata| {
+        use std::os::unix::fs::PermissionsExt;
+        let executable = metadata.is_dir() || metadata.permissions().mode() & 0o111 != 0;
+        let mode = if executable {
+            mode.executable
+        } else {
+            mode.file
+        };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("setting permissions of {}", path.display()))
+    })?;
+    #[cfg(not(unix))]
+    tracing::warn!("ignoring --mode {mode}, permission modes are only supported on unix");
+}
+
+/// Removes write permission from everything in the tree
+#[culpa::throws]
+#[fn_error_context::context("making {} read-only", dir.display())]
+pub(crate) fn make_read_only(dir: &Path) {
+    walk(dir, &mut |path, metadata| {
+        let mut permissions = metadata.permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(path, permissions)
+            .with_context(|| format!("setting permissions of {}", path.display()))
+    })?;
+}
+
+/// Whether following `target` from `dir` leads outside the root `dir` is relative to
+fn escapes(dir: &Path, target: &Path) -> bool {
+    let mut depth = dir.components().count();
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+    false
+}
+
+/// Folds the case of a path, so that checks against it also hold on case-insensitive filesystems
+fn fold(path: &Path) -> PathBuf {
+    PathBuf::from(path.to_string_lossy().to_lowercase())
+}
+
+/// The symlinks unpacked so far, so that nothing is unpacked through them and no link resolves
+/// through another, where the lexical checks could be bypassed by chaining links
+#[derive(Debug, Default)]
+struct Links {
+    /// The archive path of each link by its folded path
+    links: std::collections::HashMap<PathBuf, String>,
+    /// The directories that link targets pass through, with the link that does
+    traversed: std::collections::HashMap<PathBuf, String>,
+}
+
+impl Links {
+    /// The archive path of the link that the path is inside of, if any
+    fn containing(&self, path: &Path) -> Option<&str> {
+        path.ancestors()
+            .skip(1)
+            .find_map(|ancestor| self.links.get(&fold(ancestor)))
+            .map(String::as_str)
+    }
+
+    /// Whether the path is a link or inside of one
+    fn is_link_or_inside(&self, path: &Path) -> bool {
+        path.ancestors()
+            .any(|ancestor| self.links.contains_key(&fold(ancestor)))
+    }
+
+    /// Records a link to `target`, which must already have been checked to not escape
+    fn add(&mut self, link: &Path, display: &str, target: &Path) -> Result<(), Violation> {
+        if let Some(other) = self.traversed.get(&fold(link)) {
+            return Err(Violation::LinkThroughLink(
+                other.clone(),
+                display.to_owned(),
+            ));
+        }
+        let mut current = link.parent().unwrap_or(Path::new("")).to_owned();
+        let mut components = target.components().peekable();
+        while let Some(component) = components.next() {
+            match component {
+                Component::Normal(name) => current.push(name),
+                Component::ParentDir => {
+                    current.pop();
+                    continue;
+                }
+                _ => continue,
+            }
+            // Linking to another link is fine, only going through one could escape
+            if components.peek().is_none() {
+                break;
+            }
+            if let Some(other) = self.links.get(&fold(&current)) {
+                return Err(Violation::LinkThroughLink(
+                    display.to_owned(),
+                    other.clone(),
+                ));
+            }
+            self.traversed.insert(fold(&current), display.to_owned());
+        }
+        self.links.insert(fold(link), display.to_owned());
+        Ok(())
+    }
+}
 
 #[culpa::throws]
 pub(crate) fn unpack(
-    version: &crates_index::Version,
+    name: &str,
+    version: &str,
     mut archive: tar::Archive<impl std::io::Read>,
     output: impl AsRef<Path>,
+    options: &Options,
 ) {
-    let base = format!("{}-{}", version.name(), version.version());
+    let include = glob_set(&options.include)?;
+    let exclude = glob_set(&options.exclude)?;
+    let mut included = vec![false; options.include.len()];
+    let mut portability = crate::portability::Check::default();
+    let (mut original, mut normalized) = (false, None);
+    let base = format!("{name}-{version}");
     let output = output.as_ref();
     std::fs::create_dir_all(output)?;
-    let mut entries = archive.entries()?;
+    let mut links = Links::default();
+    let mut entries = options.limits.entries(&mut archive)?;
     while let Some(mut entry) = entries.next().transpose()? {
-        let path = entry.path()?;
+        let path = entry.path()?.into_owned();
+        let display = path.display().to_string();
         if path.components().any(|c| {
             matches!(
                 c,
                 Component::ParentDir | Component::RootDir | Component::Prefix(_)
             )
         }) {
-            culpa::throw!(anyhow!(
-                "a file in the archive ({}) contains a .. or root segment",
-                path.display()
-            ));
+            culpa::throw!(Violation::Path(display));
+        }
+
+        let entry_type = entry.header().entry_type();
+        let swapped = options
+            .original_manifest
+            .then(|| crate::manifest::swap(&base, &path))
+            .flatten();
+        if let Some(swapped) = &swapped {
+            original |= swapped.ends_with(crate::manifest::NORMALIZED);
         }
-        let dst = output.join(path.strip_prefix(&base)?);
+        let relative = match options.layout.map(
+            &base,
+            swapped.as_deref().unwrap_or(&path),
+            entry_type.is_dir(),
+        ) {
+            Ok(Some(relative)) => relative,
+            Ok(None) => continue,
+            Err(reason) => culpa::throw!(Violation::Misplaced(display, reason)),
+        };
+        let relative = relative.as_path();
+        if !options.include.is_empty() {
+            let matches = include.matches(relative);
+            let Some(&first) = matches.first() else {
+                continue;
+            };
+            for &index in &matches {
+                included[index] = true;
+            }
+            tracing::debug!(
+                "{} matched --include {}",
+                relative.display(),
+                options.include[first]
+            );
+        }
+        if let Some(&first) = exclude.matches(relative).first() {
+            tracing::debug!(
+                "{} matched --exclude {}",
+                relative.display(),
+                options.exclude[first]
+            );
+            continue;
+        }
+        if let Some(link) = links.containing(relative) {
+            culpa::throw!(Violation::ThroughLink(display, link.to_owned()));
+        }
+        portability.entry(&base, relative);
+        let dst = output.join(relative);
+        match entry_type {
+            tar::EntryType::Regular | tar::EntryType::Directory => {}
+            tar::EntryType::Symlink => {
+                let target = entry
+                    .link_name()?
+                    .with_context(|| format!("{display} is a symlink without a target"))?;
+                let dir = relative.parent().unwrap_or(Path::new(""));
+                if escapes(dir, &target) {
+                    culpa::throw!(Violation::EscapingLink(
+                        display,
+                        target.display().to_string()
+                    ));
+                }
+                links.add(relative, &display, &target)?;
+            }
+            tar::EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .with_context(|| format!("{display} is a hard link without a target"))?
+                    .into_owned();
+                let escaping =
+                    || Violation::EscapingLink(display.clone(), target.display().to_string());
+                let relative_target = options
+                    .layout
+                    .map(&base, &target, false)
+                    .ok()
+                    .flatten()
+                    .ok_or_else(escaping)?;
+                if escapes(Path::new(""), &relative_target)
+                    || links.is_link_or_inside(&relative_target)
+                {
+                    culpa::throw!(escaping());
+                }
+                let source = output.join(&relative_target);
+                if !source
+                    .symlink_metadata()
+                    .is_ok_and(|metadata| metadata.is_file())
+                {
+                    culpa::throw!(Violation::MissingLinkTarget(
+                        display,
+                        target.display().to_string()
+                    ));
+                }
+                std::fs::create_dir_all(dst.parent().context("file missing parent")?)?;
+                std::fs::hard_link(&source, &dst).with_context(|| {
+                    format!("linking {} to {}", dst.display(), source.display())
+                })?;
+                continue;
+            }
+            tar::EntryType::XGlobalHeader => continue,
+            _ => culpa::throw!(Violation::EntryType(display, entry_type)),
+        }
+
         std::fs::create_dir_all(dst.parent().context("file missing parent")?)?;
-        entry.unpack(dst)?;
+        entry.set_preserve_mtime(options.mtime == Mtime::Archive);
+        entry
+            .unpack(&dst)
+            .with_context(|| format!("unpacking {display}"))?;
+        if swapped.is_some_and(|swapped| swapped.ends_with(crate::manifest::KEPT_NORMALIZED)) {
+            normalized = Some(dst);
+        }
+    }
+
+    if let Some(normalized) = normalized.filter(|_| !original) {
+        tracing::warn!(
+            "{name} {version} has no {}, keeping the normalized manifest",
+            crate::manifest::ORIGINAL
+        );
+        std::fs::rename(
+            &normalized,
+            normalized.with_file_name(crate::manifest::NORMALIZED),
+        )?;
+    }
+
+    if let Some((glob, _)) = options
+        .include
+        .iter()
+        .zip(included)
+        .find(|(_, included)| !included)
+    {
+        culpa::throw!(anyhow!(
+            "--include {glob} matched nothing in {name} {version}"
+        ));
+    }
+    portability.finish(name, version, options.deny_unportable)?;
+
+    if let Some(mode) = options.mode {
+        set_modes(output, mode)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cat, escapes, make_read_only, parse_glob, unpack, validate, Layout, Limits, Mode, Mtime,
+        Options, Violation,
+    };
+    use std::path::{Path, PathBuf};
+
+    /// The path, type, contents and link target of an archive entry
+    type Entry<'a> = (&'a str, tar::EntryType, &'a [u8], Option<&'a str>);
+
+    /// Builds an archive of `(path, type, contents, link)` entries, without the path validation
+    /// `tar` does so that malicious archives can be built too
+    fn archive(entries: &[Entry<'_>]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for &(path, entry_type, contents, link) in entries {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            if let Some(link) = link {
+                header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            }
+            header.set_entry_type(entry_type);
+            header.set_mode(if entry_type.is_dir() { 0o755 } else { 0o644 });
+            header.set_size(contents.len() as u64);
+            header.set_mtime(1);
+            header.set_cksum();
+            builder.append(&header, contents).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn file<'a>(path: &'a str, contents: &'a [u8]) -> Entry<'a> {
+        (path, tar::EntryType::Regular, contents, None)
+    }
+
+    fn link<'a>(path: &'a str, target: &'a str) -> Entry<'a> {
+        (path, tar::EntryType::Symlink, b"", Some(target))
+    }
+
+    fn options() -> Options {
+        Options {
+            layout: Layout::Crate,
+            limits: Limits {
+                entries: 100,
+                bytes: 1000,
+            },
+            mode: None,
+            mtime: Mtime::Archive,
+            deny_unportable: false,
+            original_manifest: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+
+    /// Unpacks the entries of `foo 0.1.0` into a temporary directory
+    fn unpack_with(
+        entries: &[Entry<'_>],
+        options: &Options,
+    ) -> (tempfile::TempDir, Result<(), anyhow::Error>) {
+        let dir = tempfile::tempdir().unwrap();
+        let data = archive(entries);
+        let result = unpack(
+            "foo",
+            "0.1.0",
+            tar::Archive::new(&data[..]),
+            dir.path().join("out"),
+            options,
+        );
+        (dir, result)
+    }
+
+    /// The paths of every file and link unpacked to the directory, relative to it
+    fn unpacked(dir: &Path) -> Vec<String> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() && !path.is_symlink() {
+                paths.extend(unpacked(&path).into_iter().map(|inner| {
+                    format!("{}/{inner}", path.file_name().unwrap().to_string_lossy())
+                }));
+            } else {
+                paths.push(path.file_name().unwrap().to_string_lossy().into_owned());
+            }
+        }
+        paths.sort();
+        paths
+    }
+
+    fn violation(entries: &[Entry<'_>]) -> Violation {
+        unpack_with(entries, &options())
+            .1
+            .unwrap_err()
+            .downcast()
+            .unwrap()
+    }
+
+    #[test]
+    fn escaping_targets() {
+        assert!(!escapes(Path::new(""), Path::new("src/lib.rs")));
+        assert!(!escapes(Path::new("src"), Path::new("../README.md")));
+        assert!(!escapes(Path::new("a/b"), Path::new("./../../c")));
+        assert!(escapes(Path::new(""), Path::new("../x")));
+        assert!(escapes(Path::new("src"), Path::new("../../x")));
+        assert!(escapes(Path::new("src"), Path::new("/etc/passwd")));
+    }
+
+    #[test]
+    fn unpacks_files_and_links() {
+        let (dir, result) = unpack_with(
+            &[
+                file("foo-0.1.0/src/lib.rs", b"lib"),
+                link("foo-0.1.0/src/alias.rs", "lib.rs"),
+                link("foo-0.1.0/docs", "src"),
+            ],
+            &options(),
+        );
+        result.unwrap();
+        let out = dir.path().join("out");
+        assert_eq!(std::fs::read(out.join("src/lib.rs")).unwrap(), b"lib");
+        assert_eq!(std::fs::read(out.join("src/alias.rs")).unwrap(), b"lib");
+        assert_eq!(
+            std::fs::read_link(out.join("docs")).unwrap(),
+            Path::new("src")
+        );
+    }
+
+    #[test]
+    fn rejects_escaping_paths() {
+        assert!(matches!(
+            violation(&[file("foo-0.1.0/../x", b"")]),
+            Violation::Path(_)
+        ));
+        assert!(matches!(
+            violation(&[file("/foo-0.1.0/x", b"")]),
+            Violation::Path(_)
+        ));
+        assert!(matches!(
+            violation(&[link("foo-0.1.0/src/evil", "../../../etc/passwd")]),
+            Violation::EscapingLink(..)
+        ));
+        assert!(matches!(
+            violation(&[(
+                "foo-0.1.0/evil",
+                tar::EntryType::Link,
+                b"",
+                Some("/etc/passwd")
+            )]),
+            Violation::EscapingLink(..)
+        ));
+    }
+
+    #[test]
+    fn rejects_entries_through_links() {
+        assert!(matches!(
+            violation(&[link("foo-0.1.0/d", "src"), file("foo-0.1.0/d/x", b"")]),
+            Violation::ThroughLink(..)
+        ));
+        assert!(matches!(
+            violation(&[link("foo-0.1.0/d", "src"), file("foo-0.1.0/D/x", b"")]),
+            Violation::ThroughLink(..)
+        ));
+        assert!(matches!(
+            violation(&[link("foo-0.1.0/d", "."), link("foo-0.1.0/d/l", "..")]),
+            Violation::ThroughLink(..)
+        ));
+    }
+
+    #[test]
+    fn rejects_link_chains() {
+        assert!(matches!(
+            violation(&[link("foo-0.1.0/a", "."), link("foo-0.1.0/b", "a/..")]),
+            Violation::LinkThroughLink(..)
+        ));
+        assert!(matches!(
+            violation(&[link("foo-0.1.0/b", "a/.."), link("foo-0.1.0/a", ".")]),
+            Violation::LinkThroughLink(..)
+        ));
+        assert!(matches!(
+            violation(&[link("foo-0.1.0/A", "."), link("foo-0.1.0/b", "a/..")]),
+            Violation::LinkThroughLink(..)
+        ));
+    }
+
+    #[test]
+    fn rejects_hard_links_to_missing_files() {
+        assert!(matches!(
+            violation(&[(
+                "foo-0.1.0/b",
+                tar::EntryType::Link,
+                b"",
+                Some("foo-0.1.0/a")
+            )]),
+            Violation::MissingLinkTarget(..)
+        ));
+        assert!(matches!(
+            violation(&[
+                link("foo-0.1.0/a", "src"),
+                (
+                    "foo-0.1.0/b",
+                    tar::EntryType::Link,
+                    b"",
+                    Some("foo-0.1.0/a")
+                ),
+            ]),
+            Violation::EscapingLink(..)
+        ));
+    }
+
+    #[test]
+    fn rejects_special_files() {
+        assert!(matches!(
+            violation(&[("foo-0.1.0/pipe", tar::EntryType::Fifo, b"", None)]),
+            Violation::EntryType(_, tar::EntryType::Fifo)
+        ));
+    }
+
+    #[test]
+    fn limits() {
+        let mut options = options();
+        options.limits.entries = 2;
+        let (_dir, result) = unpack_with(
+            &[
+                file("foo-0.1.0/a", b""),
+                file("foo-0.1.0/b", b""),
+                file("foo-0.1.0/c", b""),
+            ],
+            &options,
+        );
+        assert!(
+            matches!(result.unwrap_err().downcast().unwrap(), Violation::TooManyEntries(path, 2) if path == "foo-0.1.0/c")
+        );
+
+        let mut options = self::options();
+        options.limits.bytes = 5;
+        let (_dir, result) = unpack_with(
+            &[file("foo-0.1.0/a", b"abc"), file("foo-0.1.0/b", b"abc")],
+            &options,
+        );
+        assert!(
+            matches!(result.unwrap_err().downcast().unwrap(), Violation::TooLarge(path, 5) if path == "foo-0.1.0/b")
+        );
+    }
+
+    #[test]
+    fn reader_limits() {
+        let data = archive(&[
+            file("foo-0.1.0/a", b"abc"),
+            file("foo-0.1.0/b", b"abc"),
+            file("foo-0.1.0/c", b"abc"),
+        ]);
+        let limits = Limits {
+            entries: 2,
+            bytes: 1000,
+        };
+        let error = validate(
+            "foo",
+            "0.1.0",
+            tar::Archive::new(&data[..]),
+            &Layout::Crate,
+            limits,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(error.downcast().unwrap(), Violation::TooManyEntries(path, 2) if path == "foo-0.1.0/c")
+        );
+
+        let limits = Limits {
+            entries: 100,
+            bytes: 5,
+        };
+        let globs = [parse_glob("a").unwrap()];
+        let mut out = Vec::new();
+        let error = cat(
+            "foo",
+            "0.1.0",
+            tar::Archive::new(&data[..]),
+            &Layout::Crate,
+            limits,
+            &globs,
+            &mut out,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(error.downcast().unwrap(), Violation::TooLarge(path, 5) if path == "foo-0.1.0/b")
+        );
+    }
+
+    #[test]
+    fn crate_layout() {
+        let map = |path: &str, is_dir| Layout::Crate.map("foo-0.1.0", Path::new(path), is_dir);
+        assert_eq!(
+            map("foo-0.1.0/src/lib.rs", false),
+            Ok(Some(PathBuf::from("src/lib.rs")))
+        );
+        assert_eq!(map("foo-0.1.0", true), Ok(None));
+        assert_eq!(
+            map("foo-0.1.0", false),
+            Err("would replace the output directory itself".to_owned())
+        );
+        assert_eq!(
+            map("bar-0.1.0/src/lib.rs", false),
+            Err("is under bar-0.1.0/ instead of foo-0.1.0/".to_owned())
+        );
+        assert_eq!(
+            map("bar-0.1.0", true),
+            Err("is under bar-0.1.0/ instead of foo-0.1.0/".to_owned())
+        );
+        assert_eq!(
+            map("README", false),
+            Err("is at the top level instead of under foo-0.1.0/".to_owned())
+        );
+    }
+
+    #[test]
+    fn strip_layout() {
+        let map = |count, path: &str, is_dir| {
+            Layout::Strip(count).map("foo-0.1.0", Path::new(path), is_dir)
+        };
+        assert_eq!(
+            map(1, "other/src/lib.rs", false),
+            Ok(Some(PathBuf::from("src/lib.rs")))
+        );
+        assert_eq!(
+            map(2, "other/src/lib.rs", false),
+            Ok(Some(PathBuf::from("lib.rs")))
+        );
+        assert_eq!(
+            map(0, "other/src/lib.rs", false),
+            Ok(Some(PathBuf::from("other/src/lib.rs")))
+        );
+        assert_eq!(map(2, "other/src", true), Ok(None));
+        assert_eq!(
+            map(2, "other/README", false),
+            Err("has too few path components (2) to strip 2".to_owned())
+        );
+    }
+
+    #[test]
+    fn misplaced_entries() {
+        let data = archive(&[
+            file("foo-0.1.0/src/lib.rs", b""),
+            file("README", b""),
+            file("other/x.rs", b""),
+        ]);
+        let error = validate(
+            "foo",
+            "0.1.0",
+            tar::Archive::new(&data[..]),
+            &Layout::Crate,
+            options().limits,
+        )
+        .unwrap_err()
+        .to_string();
+        assert_eq!(
+            error,
+            "2 entries in the archive are misplaced:\n  \
+             README is at the top level instead of under foo-0.1.0/\n  \
+             other/x.rs is under other/ instead of foo-0.1.0/\n\
+             use --strip-components or --keep-top-level to extract it anyway"
+        );
+        validate(
+            "foo",
+            "0.1.0",
+            tar::Archive::new(&data[..]),
+            &Layout::Strip(0),
+            options().limits,
+        )
+        .unwrap();
+
+        let mut options = options();
+        options.layout = Layout::Strip(0);
+        let (dir, result) = unpack_with(
+            &[file("foo-0.1.0/src/lib.rs", b""), file("README", b"")],
+            &options,
+        );
+        result.unwrap();
+        assert_eq!(
+            unpacked(&dir.path().join("out")),
+            ["README", "foo-0.1.0/src/lib.rs"]
+        );
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let entries = [
+            file("foo-0.1.0/Cargo.toml", b""),
+            file("foo-0.1.0/src/lib.rs", b""),
+            file("foo-0.1.0/src/bin/main.rs", b""),
+            file("foo-0.1.0/tests/it.rs", b""),
+        ];
+
+        let mut options = options();
+        options.include = vec![
+            parse_glob("src/*").unwrap(),
+            parse_glob("Cargo.toml").unwrap(),
+        ];
+        let (dir, result) = unpack_with(&entries, &options);
+        result.unwrap();
+        assert_eq!(
+            unpacked(&dir.path().join("out")),
+            ["Cargo.toml", "src/lib.rs"]
+        );
+
+        options.include = vec![parse_glob("src/**").unwrap()];
+        options.exclude = vec![parse_glob("**/main.rs").unwrap()];
+        let (dir, result) = unpack_with(&entries, &options);
+        result.unwrap();
+        assert_eq!(unpacked(&dir.path().join("out")), ["src/lib.rs"]);
+
+        options.include = vec![
+            parse_glob("src/**").unwrap(),
+            parse_glob("benches/**").unwrap(),
+        ];
+        options.exclude = Vec::new();
+        let (_dir, result) = unpack_with(&entries, &options);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "--include benches/** matched nothing in foo 0.1.0"
+        );
+    }
+
+    #[test]
+    fn excluded_entries_are_skipped() {
+        let mut options = options();
+        options.exclude = vec![parse_glob("d/**").unwrap()];
+        let (dir, result) = unpack_with(
+            &[link("foo-0.1.0/d", "src"), file("foo-0.1.0/d/x", b"")],
+            &options,
+        );
+        result.unwrap();
+        assert_eq!(unpacked(&dir.path().join("out")), ["d"]);
+
+        options.exclude = vec![parse_glob("fifo").unwrap()];
+        let (dir, result) = unpack_with(
+            &[
+                file("foo-0.1.0/src/lib.rs", b""),
+                ("foo-0.1.0/fifo", tar::EntryType::Fifo, b"", None),
+            ],
+            &options,
+        );
+        result.unwrap();
+        assert_eq!(unpacked(&dir.path().join("out")), ["src/lib.rs"]);
+    }
+
+    #[test]
+    fn cat_files() {
+        let data = archive(&[
+            file("foo-0.1.0/Cargo.toml", b"[package]\n"),
+            file("foo-0.1.0/src/lib.rs", b"lib\n"),
+            file("foo-0.1.0/src/main.rs", b"main\n"),
+            link("foo-0.1.0/src/alias.rs", "lib.rs"),
+        ]);
+        let print = |globs: &[&str]| {
+            let globs = Vec::from_iter(globs.iter().map(|glob| parse_glob(glob).unwrap()));
+            let mut out = Vec::new();
+            let printed = cat(
+                "foo",
+                "0.1.0",
+                tar::Archive::new(&data[..]),
+                &Layout::Crate,
+                options().limits,
+                &globs,
+                &mut out,
+            )?;
+            Ok::<_, anyhow::Error>((printed, String::from_utf8(out).unwrap()))
+        };
+        assert_eq!(print(&["src/*.rs"]).unwrap(), (2, "lib\nmain\n".to_owned()));
+        assert_eq!(
+            print(&["Cargo.toml", "src/lib.rs"]).unwrap(),
+            (2, "[package]\nlib\n".to_owned())
+        );
+        assert_eq!(print(&["src/alias.rs"]).unwrap(), (0, String::new()));
+        assert_eq!(
+            print(&["src/lib.rs", "README.md"]).unwrap_err().to_string(),
+            "--cat README.md matched nothing in foo 0.1.0"
+        );
+    }
+
+    #[test]
+    fn parse_modes() {
+        let mode = |s: &str| s.parse::<Mode>().map(|mode| mode.to_string());
+        assert_eq!(mode("644").unwrap(), "0644/0755");
+        assert_eq!(mode("0640").unwrap(), "0640/0750");
+        assert_eq!(mode("600/700").unwrap(), "0600/0700");
+        assert!(mode("1777").is_err());
+        assert!(mode("rw").is_err());
+        assert!(mode("644/").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, mode) in [("foo-0.1.0/src/lib.rs", 0o664), ("foo-0.1.0/run.sh", 0o775)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(mode);
+            header.set_size(0);
+            builder.append_data(&mut header, path, &b""[..]).unwrap();
+        }
+        let data = builder.into_inner().unwrap();
+
+        let mut options = options();
+        options.mode = Some("600".parse().unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        unpack("foo", "0.1.0", tar::Archive::new(&data[..]), &out, &options).unwrap();
+        let mode = |path: &str| out.join(path).metadata().unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode("src/lib.rs"), 0o600);
+        assert_eq!(mode("run.sh"), 0o700);
+        assert_eq!(mode("src"), 0o700);
+
+        make_read_only(&out).unwrap();
+        assert_eq!(mode("src/lib.rs"), 0o400);
+        assert_eq!(mode("src"), 0o500);
+        assert_eq!(mode(""), 0o500);
+
+        // Let the temporary directory be cleaned up
+        for path in [out.join("src"), out.clone()] {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o700)).unwrap();
+        }
+    }
+
+    #[test]
+    fn mtimes() {
+        let entries = [file("foo-0.1.0/src/lib.rs", b"")];
+        let mtime = |dir: &Path| {
+            dir.join("out/src/lib.rs")
+                .metadata()
+                .unwrap()
+                .modified()
+                .unwrap()
+        };
+
+        let (dir, result) = unpack_with(&entries, &options());
+        result.unwrap();
+        assert_eq!(
+            mtime(dir.path()),
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1)
+        );
+
+        let mut options = options();
+        options.mtime = Mtime::Now;
+        let (dir, result) = unpack_with(&entries, &options);
+        result.unwrap();
+        assert!(mtime(dir.path()) > std::time::UNIX_EPOCH + std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn original_manifest() {
+        let entries = [
+            file("foo-0.1.0/Cargo.toml", b"normalized"),
+            file("foo-0.1.0/Cargo.toml.orig", b"original"),
+        ];
+        let mut options = options();
+        options.original_manifest = true;
+        let (dir, result) = unpack_with(&entries, &options);
+        result.unwrap();
+        let out = dir.path().join("out");
+        assert_eq!(unpacked(&out), ["Cargo.toml", "Cargo.toml.normalized"]);
+        assert_eq!(std::fs::read(out.join("Cargo.toml")).unwrap(), b"original");
+
+        // Without an original manifest the normalized one is kept in place
+        let (dir, result) = unpack_with(&entries[..1], &options);
+        result.unwrap();
+        let out = dir.path().join("out");
+        assert_eq!(unpacked(&out), ["Cargo.toml"]);
+        assert_eq!(
+            std::fs::read(out.join("Cargo.toml")).unwrap(),
+            b"normalized"
+        );
     }
 }
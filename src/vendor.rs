@@ -0,0 +1,426 @@
+use crate::{
+    package_id_spec::{GitReference, GitSource, Source},
+    version::Version,
+};
+use anyhow::{anyhow, Context, Error};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+pub(crate) const CHECKSUM_FILE: &str = ".cargo-checksum.json";
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Checksums {
+    pub(crate) files: BTreeMap<String, String>,
+    pub(crate) package: Option<String>,
+}
+
+#[culpa::throws]
+#[fn_error_context::context("hashing {}", path.display())]
+fn sha256_file(path: &Path) -> String {
+    use sha2::Digest;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    hex::encode(hasher.finalize())
+}
+
+#[culpa::throws]
+fn hash_dir(root: &Path, dir: &Path, files: &mut BTreeMap<String, String>) {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            hash_dir(root, &path, files)?;
+        } else if file_type.is_file() {
+            let relative = path.strip_prefix(root)?;
+            if relative == Path::new(CHECKSUM_FILE) {
+                continue;
+            }
+            let relative = Vec::from_iter(
+                relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy()),
+            );
+            files.insert(relative.join("/"), sha256_file(&path)?);
+        }
+    }
+}
+
+/// Calculates the checksums of every file in an extracted crate
+#[culpa::throws]
+#[fn_error_context::context("calculating checksums of {}", dir.display())]
+pub(crate) fn checksums(dir: &Path, package: Option<&[u8; 32]>) -> Checksums {
+    let mut files = BTreeMap::new();
+    hash_dir(dir, dir, &mut files)?;
+    Checksums {
+        files,
+        package: package.map(hex::encode),
+    }
+}
+
+/// Writes the `.cargo-checksum.json` cargo requires for directory sources
+#[culpa::throws]
+#[fn_error_context::context("writing checksums for {}", dir.display())]
+pub(crate) fn write_checksums(dir: &Path, package: Option<&[u8; 32]>) {
+    let checksums = checksums(dir, package)?;
+    std::fs::write(dir.join(CHECKSUM_FILE), serde_json::to_string(&checksums)?)?;
+}
+
+/// Checks that the files in a vendored crate still match its `.cargo-checksum.json`
+#[culpa::throws]
+#[fn_error_context::context("verifying checksums of {}", dir.display())]
+pub(crate) fn verify(dir: &Path) {
+    let path = dir.join(CHECKSUM_FILE);
+    let expected: Checksums = serde_json::from_str(
+        &std::fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?,
+    )?;
+    let actual = checksums(dir, None)?;
+    let mut mismatched = Vec::new();
+    for (file, checksum) in &expected.files {
+        match actual.files.get(file) {
+            Some(actual) if actual == checksum => {}
+            Some(_) => mismatched.push(format!("{file} (modified)")),
+            None => mismatched.push(format!("{file} (removed)")),
+        }
+    }
+    for file in actual.files.keys() {
+        if !expected.files.contains_key(file) {
+            mismatched.push(format!("{file} (added)"));
+        }
+    }
+    if !mismatched.is_empty() {
+        culpa::throw!(anyhow!(
+            "files do not match their checksums, refusing to modify it: {}",
+            mismatched.join(", ")
+        ));
+    }
+}
+
+/// The existing vendored copies of a crate, along with their versions
+#[culpa::throws]
+#[fn_error_context::context("finding vendored copies of {name} in {}", dir.display())]
+pub(crate) fn find(dir: &Path, name: &str) -> Vec<(PathBuf, String)> {
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        {
+            continue;
+        }
+        let manifest = path.join("Cargo.toml");
+        if !manifest.is_file() {
+            continue;
+        }
+        let manifest: toml::Table = toml::from_str(
+            &std::fs::read_to_string(&manifest)
+                .with_context(|| format!("reading {}", manifest.display()))?,
+        )
+        .with_context(|| format!("parsing {}", manifest.display()))?;
+        let package = manifest
+            .get("package")
+            .and_then(|package| package.as_table());
+        let field = |key| {
+            package
+                .and_then(|package| package.get(key))
+                .and_then(|value| value.as_str())
+        };
+        if let (Some(package_name), Some(version)) = (field("name"), field("version")) {
+            if package_name == name {
+                found.push((path, version.to_owned()));
+            }
+        }
+    }
+    found
+}
+
+/// Whether `new` can replace the vendored `old` version, i.e. they are the same version or
+/// semver compatible
+pub(crate) fn replaces(old: &str, new: &str) -> bool {
+    match (Version::parse(old), Version::parse(new)) {
+        (Version::Semver(old), Version::Semver(new)) => {
+            semver::Comparator {
+                op: semver::Op::Caret,
+                major: old.major,
+                minor: Some(old.minor),
+                patch: Some(old.patch),
+                pre: old.pre.clone(),
+            }
+            .matches(&new)
+                || old == new
+        }
+        _ => old == new,
+    }
+}
+
+/// Where an added or upgraded copy of a crate goes when updating a vendor directory, replacing
+/// the existing copy at `replaced` if any.
+///
+/// Like `cargo vendor`, the directory is unversioned unless another copy of the crate already
+/// uses that name, so a versioned copy being upgraded is renamed to match its new version.
+pub(crate) fn update_path(
+    dir: &Path,
+    name: &str,
+    version: &str,
+    replaced: Option<&Path>,
+) -> PathBuf {
+    let unversioned = dir.join(name);
+    if replaced == Some(unversioned.as_path()) || !unversioned.exists() {
+        unversioned
+    } else {
+        dir.join(format!("{name}-{version}"))
+    }
+}
+
+/// Renames the newest version of each crate to an unversioned directory, the same layout that
+/// `cargo vendor` uses, replacing an existing directory only if `force` is set
+#[culpa::throws]
+#[fn_error_context::context("finishing vendor directory {}", dir.display())]
+pub(crate) fn finish(dir: &Path, vendored: &[(String, String)], force: bool) {
+    let mut versions = BTreeMap::<&str, Vec<Version>>::new();
+    for (name, version) in vendored {
+        versions
+            .entry(name)
+            .or_default()
+            .push(Version::parse(version));
+    }
+    for (name, versions) in versions {
+        let newest = versions.iter().max().context("no versions")?;
+        let (from, to) = (dir.join(format!("{name}-{newest}")), dir.join(name));
+        if to.exists() {
+            if !force {
+                culpa::throw!(anyhow!("{} already exists", to.display()));
+            }
+            std::fs::remove_dir_all(&to).with_context(|| format!("removing {}", to.display()))?;
+        }
+        std::fs::rename(&from, &to)
+            .with_context(|| format!("renaming {} to {}", from.display(), to.display()))?;
+    }
+}
+
+/// A TOML string, quoted and escaped as needed
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_owned()).to_string()
+}
+
+/// The source replacement configuration needed to build using the sources from a replacement
+/// source of kind `key` (e.g. `directory`) at `location`
+#[culpa::throws]
+pub(crate) fn config(name: &str, key: &str, location: &str, sources: &BTreeSet<Source>) -> String {
+    let mut config = String::new();
+    for source in sources {
+        match source {
+            Source::Registry(url) if url == crates_index::sparse::URL => {
+                writeln!(config, "[source.crates-io]")?;
+            }
+            Source::Registry(url) => {
+                writeln!(config, "[source.{}]", quote(&source.to_string()))?;
+                writeln!(config, "registry = {}", quote(url))?;
+            }
+            Source::Git(GitSource { url, reference }) => {
+                writeln!(config, "[source.{}]", quote(&source.to_string()))?;
+                writeln!(config, "git = {}", quote(url))?;
+                match reference {
+                    Some(GitReference::Rev(rev)) => writeln!(config, "rev = {}", quote(rev))?,
+                    Some(GitReference::Branch(branch)) => {
+                        writeln!(config, "branch = {}", quote(branch))?
+                    }
+                    Some(GitReference::Tag(tag)) => writeln!(config, "tag = {}", quote(tag))?,
+                    None => {}
+                }
+            }
+        }
+        writeln!(config, "replace-with = {}", quote(name))?;
+        writeln!(config)?;
+    }
+    writeln!(config, "[source.{name}]")?;
+    writeln!(config, "{key} = {}", quote(location))?;
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        checksums, config, find, finish, replaces, update_path, verify, write_checksums,
+        CHECKSUM_FILE,
+    };
+    use crate::package_id_spec::{GitReference, GitSource, Source};
+    use std::{collections::BTreeSet, path::Path};
+
+    fn write(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn checksums_of_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write(&dir.path().join("src/lib.rs"), "");
+        write(&dir.path().join("Cargo.toml"), "[package]\n");
+        write_checksums(dir.path(), Some(&[1; 32])).unwrap();
+
+        let checksums = checksums(dir.path(), None).unwrap();
+        assert_eq!(
+            Vec::from_iter(checksums.files.keys()),
+            ["Cargo.toml", "src/lib.rs"],
+            "the checksum file itself is not included"
+        );
+        assert_eq!(
+            checksums.files["src/lib.rs"],
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join(CHECKSUM_FILE)).unwrap())
+                .unwrap();
+        assert_eq!(written["package"], hex::encode([1; 32]));
+    }
+
+    #[test]
+    fn verify_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        write(&dir.path().join("a"), "a");
+        write(&dir.path().join("b"), "b");
+        write_checksums(dir.path(), None).unwrap();
+        verify(dir.path()).unwrap();
+
+        write(&dir.path().join("a"), "changed");
+        std::fs::remove_file(dir.path().join("b")).unwrap();
+        write(&dir.path().join("c"), "c");
+        let error = format!("{:#}", verify(dir.path()).unwrap_err());
+        assert!(
+            error.ends_with("a (modified), b (removed), c (added)"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn finish_renames_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["foo-1.0.0", "foo-1.10.0", "foo-1.9.0", "bar-0.1.0"] {
+            write(&dir.path().join(name).join("Cargo.toml"), "");
+        }
+        let vendored = Vec::from_iter(
+            [
+                ("foo", "1.0.0"),
+                ("foo", "1.10.0"),
+                ("foo", "1.9.0"),
+                ("bar", "0.1.0"),
+            ]
+            .map(|(name, version)| (name.to_owned(), version.to_owned())),
+        );
+        finish(dir.path(), &vendored, false).unwrap();
+        let mut entries = Vec::from_iter(
+            std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap()),
+        );
+        entries.sort();
+        assert_eq!(entries, ["bar", "foo", "foo-1.0.0", "foo-1.9.0"]);
+    }
+
+    #[test]
+    fn config_quoting() {
+        let sources = BTreeSet::from([
+            Source::crates_io(),
+            Source::Git(GitSource {
+                url: "https://example.com/repo".to_owned(),
+                reference: Some(GitReference::Branch("we\"ird\\branch".to_owned())),
+            }),
+        ]);
+        let config = config("vendored-sources", "directory", "C:\\vendor\n", &sources).unwrap();
+        let parsed: toml::Table = toml::from_str(&config).unwrap();
+        let source = parsed["source"].as_table().unwrap();
+        assert_eq!(
+            source["crates-io"]["replace-with"].as_str(),
+            Some("vendored-sources")
+        );
+        let git = source["git+https://example.com/repo?branch=we\"ird\\branch"]
+            .as_table()
+            .unwrap();
+        assert_eq!(git["branch"].as_str(), Some("we\"ird\\branch"));
+        assert_eq!(
+            source["vendored-sources"]["directory"].as_str(),
+            Some("C:\\vendor\n")
+        );
+    }
+
+    #[test]
+    fn compatible_replacements() {
+        assert!(replaces("1.0.0", "1.2.0"));
+        assert!(replaces("1.2.0", "1.2.0"));
+        assert!(!replaces("1.2.0", "1.0.0"));
+        assert!(!replaces("1.2.0", "2.0.0"));
+        assert!(replaces("0.2.1", "0.2.5"));
+        assert!(!replaces("0.2.1", "0.3.0"));
+        assert!(!replaces("0.0.1", "0.0.2"));
+        assert!(!replaces("1.0.0", "1.1.0-beta"));
+        assert!(replaces("0.2.0.1", "0.2.0.1"));
+        assert!(!replaces("0.2.0.1", "0.2.0.2"));
+    }
+
+    #[test]
+    fn find_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            &dir.path().join("foo/Cargo.toml"),
+            "[package]\nname = \"foo\"\nversion = \"1.0.0\"\n",
+        );
+        write(
+            &dir.path().join("foo-0.1.0/Cargo.toml"),
+            "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n",
+        );
+        write(
+            &dir.path().join("foobar/Cargo.toml"),
+            "[package]\nname = \"foobar\"\nversion = \"1.0.0\"\n",
+        );
+        write(
+            &dir.path().join(".foo/Cargo.toml"),
+            "[package]\nname = \"foo\"\nversion = \"2.0.0\"\n",
+        );
+        write(&dir.path().join("empty/README"), "");
+        let mut found = find(dir.path(), "foo").unwrap();
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                (dir.path().join("foo"), "1.0.0".to_owned()),
+                (dir.path().join("foo-0.1.0"), "0.1.0".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn update_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        write(&path("foo-1.0.0/Cargo.toml"), "");
+
+        // A versioned copy is renamed once there is nothing to collide with
+        assert_eq!(
+            update_path(dir.path(), "foo", "1.0.5", Some(&path("foo-1.0.0"))),
+            path("foo")
+        );
+        assert_eq!(update_path(dir.path(), "foo", "2.0.0", None), path("foo"));
+
+        write(&path("foo/Cargo.toml"), "");
+        assert_eq!(
+            update_path(dir.path(), "foo", "1.0.5", Some(&path("foo-1.0.0"))),
+            path("foo-1.0.5")
+        );
+        assert_eq!(
+            update_path(dir.path(), "foo", "2.0.1", Some(&path("foo"))),
+            path("foo")
+        );
+        assert_eq!(
+            update_path(dir.path(), "foo", "3.0.0", None),
+            path("foo-3.0.0")
+        );
+    }
+}
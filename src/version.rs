@@ -0,0 +1,219 @@
+use std::cmp::Ordering;
+
+/// A version as listed in an index, old crates and some private registries may have versions
+/// that are not valid semver
+#[derive(Clone, Debug)]
+pub(crate) enum Version {
+    Semver(semver::Version),
+    Other(String),
+}
+
+/// A version requirement, either a semver requirement or an exact version string for selecting
+/// versions that are not valid semver
+#[derive(Clone, Debug)]
+pub(crate) enum VersionReq {
+    Semver(semver::VersionReq),
+    Exact(String),
+}
+
+impl Version {
+    pub(crate) fn parse(s: &str) -> Self {
+        match s.parse() {
+            Ok(version) => Version::Semver(version),
+            Err(_) => Version::Other(s.to_owned()),
+        }
+    }
+
+    pub(crate) fn is_semver(&self) -> bool {
+        matches!(self, Version::Semver(_))
+    }
+
+    /// The leading numeric components, used to order non-semver versions amongst semver ones
+    fn numeric_prefix(&self) -> Vec<u64> {
+        match self {
+            Version::Semver(version) => vec![version.major, version.minor, version.patch],
+            Version::Other(s) => {
+                let mut prefix = Vec::new();
+                for segment in s.split('.') {
+                    let digits = segment
+                        .find(|c: char| !c.is_ascii_digit())
+                        .map_or(segment, |i| &segment[..i]);
+                    let Ok(num) = digits.parse() else {
+                        break;
+                    };
+                    prefix.push(num);
+                    if digits.len() != segment.len() {
+                        break;
+                    }
+                }
+                prefix
+            }
+        }
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (mut a, mut b) = (self.numeric_prefix(), other.numeric_prefix());
+        let len = a.len().max(b.len());
+        a.resize(len, 0);
+        b.resize(len, 0);
+        a.cmp(&b).then_with(|| match (self, other) {
+            (Version::Semver(a), Version::Semver(b)) => a.cmp(b),
+            (Version::Semver(_), Version::Other(_)) => Ordering::Greater,
+            (Version::Other(_), Version::Semver(_)) => Ordering::Less,
+            (Version::Other(a), Version::Other(b)) => a.cmp(b),
+        })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl std::fmt::Display for Version {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        match self {
+            Version::Semver(version) => version.fmt(f)?,
+            Version::Other(s) => f.pad(s)?,
+        }
+    }
+}
+
+impl VersionReq {
+    pub(crate) const STAR: Self = VersionReq::Semver(semver::VersionReq::STAR);
+
+    /// The version string this requirement exactly refers to, if any
+    fn exact(&self) -> Option<String> {
+        match self {
+            VersionReq::Semver(req) => match req.comparators.as_slice() {
+                [comparator] if comparator.op == semver::Op::Exact => {
+                    let mut s = comparator.major.to_string();
+                    if let Some(minor) = comparator.minor {
+                        s.push_str(&format!(".{minor}"));
+                    }
+                    if let Some(patch) = comparator.patch {
+                        s.push_str(&format!(".{patch}"));
+                    }
+                    if !comparator.pre.is_empty() {
+                        s.push_str(&format!("-{}", comparator.pre));
+                    }
+                    Some(s)
+                }
+                _ => None,
+            },
+            VersionReq::Exact(s) => Some(s.clone()),
+        }
+    }
+
+    /// Whether the version satisfies this requirement, non-semver versions only match when
+    /// exactly specified
+    pub(crate) fn matches(&self, version: &Version) -> bool {
+        match (self, version) {
+            (VersionReq::Semver(req), Version::Semver(version)) => req.matches(version),
+            (_, Version::Other(s)) => self.exact().as_ref() == Some(s),
+            (VersionReq::Exact(s), Version::Semver(version)) => version.to_string() == *s,
+        }
+    }
+}
+
+impl std::str::FromStr for VersionReq {
+    type Err = semver::Error;
+
+    #[culpa::throws(semver::Error)]
+    fn from_str(s: &str) -> Self {
+        match s.parse() {
+            Ok(req) => VersionReq::Semver(req),
+            Err(err) => match s.strip_prefix('=') {
+                Some(exact) if !exact.trim().is_empty() => {
+                    VersionReq::Exact(exact.trim().to_owned())
+                }
+                _ => culpa::throw!(err),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for VersionReq {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        match self {
+            VersionReq::Semver(req) => req.fmt(f)?,
+            VersionReq::Exact(s) => f.pad(&format!("={s}"))?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Version, VersionReq};
+
+    #[test]
+    fn lenient_ordering() {
+        let mut versions = Vec::from_iter(
+            [
+                "1.0.0",
+                "0.2.0.1",
+                "0.2.0",
+                "0.10",
+                "0.2.1",
+                "1.0.0-beta",
+                "0.1",
+            ]
+            .into_iter()
+            .map(Version::parse),
+        );
+        versions.sort();
+        assert_eq!(
+            Vec::from_iter(versions.iter().map(Version::to_string)),
+            [
+                "0.1",
+                "0.2.0",
+                "0.2.0.1",
+                "0.2.1",
+                "0.10",
+                "1.0.0-beta",
+                "1.0.0"
+            ]
+        );
+    }
+
+    #[test]
+    fn semver_sorts_after_equal_non_semver() {
+        assert!(Version::parse("1.2.0") > Version::parse("1.2"));
+        assert!(Version::parse("1.2") < Version::parse("1.2.0"));
+        assert!(Version::parse("1.2.0.1") > Version::parse("1.2.0"));
+        assert!(Version::parse("2b") > Version::parse("1.9.9"));
+    }
+
+    #[test]
+    fn exact_requirements() {
+        let req: VersionReq = "=0.2.0.1".parse().unwrap();
+        assert!(matches!(req, VersionReq::Exact(_)));
+        assert!(req.matches(&Version::parse("0.2.0.1")));
+        assert!(!req.matches(&Version::parse("0.2.0")));
+
+        let req: VersionReq = "=0.1".parse().unwrap();
+        assert!(matches!(req, VersionReq::Semver(_)));
+        assert!(req.matches(&Version::parse("0.1")));
+        assert!(req.matches(&Version::parse("0.1.3")));
+
+        let req: VersionReq = "^0.1".parse().unwrap();
+        assert!(!req.matches(&Version::parse("0.1")));
+        assert!(req.matches(&Version::parse("0.1.0")));
+
+        assert!("0.2.0.1".parse::<VersionReq>().is_err());
+        assert!("=".parse::<VersionReq>().is_err());
+    }
+}
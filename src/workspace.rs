@@ -0,0 +1,387 @@
+use crate::{
+    lockfile::{Locked, Lockfile},
+    package_id_spec::PackageIdSpec,
+    version::{Version, VersionReq},
+};
+use anyhow::{anyhow, Context, Error};
+use std::path::{Path, PathBuf};
+
+const DEPENDENCY_TABLES: &[&str] = &[
+    "dependencies",
+    "dev-dependencies",
+    "dev_dependencies",
+    "build-dependencies",
+    "build_dependencies",
+];
+
+/// The manifests and lockfile of the workspace enclosing a directory
+#[derive(Debug)]
+pub(crate) struct Workspace {
+    manifest: Option<(toml::Table, PathBuf)>,
+    root: Option<toml::Table>,
+    lockfile: Option<(Lockfile, PathBuf)>,
+}
+
+/// A dependency declared in one of the manifest's dependency tables
+struct Declared<'a> {
+    key: &'a str,
+    /// The real name of the crate, accounting for renames via `package = "..."`
+    name: &'a str,
+    value: &'a toml::Value,
+}
+
+fn find_file(dir: &Path, name: &str) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+#[culpa::throws]
+#[fn_error_context::context("reading manifest {}", path.display())]
+fn load_manifest(path: &Path) -> toml::Table {
+    toml::from_str(&std::fs::read_to_string(path)?)?
+}
+
+impl Workspace {
+    /// Finds the closest manifest and lockfile in `dir` or its parents
+    #[culpa::throws]
+    #[fn_error_context::context("finding workspace enclosing {}", dir.display())]
+    pub(crate) fn find(dir: &Path) -> Self {
+        let manifest = match find_file(dir, "Cargo.toml") {
+            Some(path) => Some((load_manifest(&path)?, path)),
+            None => None,
+        };
+
+        let mut root = None;
+        if let Some((manifest, path)) = &manifest {
+            if manifest.contains_key("workspace") {
+                root = Some(manifest.clone());
+            } else if let Some(parent) = path.parent().and_then(|dir| dir.parent()) {
+                for path in parent.ancestors().map(|dir| dir.join("Cargo.toml")) {
+                    if path.is_file() {
+                        let manifest = load_manifest(&path)?;
+                        if manifest.contains_key("workspace") {
+                            root = Some(manifest);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let lockfile = match find_file(dir, "Cargo.lock") {
+            Some(path) => Some((Lockfile::load(&path)?, path)),
+            None => None,
+        };
+
+        if manifest.is_none() && lockfile.is_none() {
+            culpa::throw!(anyhow!(
+                "could not find Cargo.toml or Cargo.lock in this or any parent directory"
+            ));
+        }
+
+        Self {
+            manifest,
+            root,
+            lockfile,
+        }
+    }
+
+    /// All declarations of the named crate in the manifest, matching either the crate name or
+    /// its renamed key
+    fn declared(&self, name: &str) -> Vec<Declared<'_>> {
+        let Some((manifest, _)) = &self.manifest else {
+            return Vec::new();
+        };
+        let targets = manifest
+            .get("target")
+            .and_then(|targets| targets.as_table())
+            .into_iter()
+            .flat_map(|targets| targets.values().filter_map(|target| target.as_table()));
+        let tables = std::iter::once(manifest)
+            .chain(targets)
+            .flat_map(|table| DEPENDENCY_TABLES.iter().filter_map(|key| table.get(*key)))
+            .chain(
+                manifest
+                    .get("workspace")
+                    .and_then(|workspace| workspace.get("dependencies")),
+            )
+            .filter_map(|table| table.as_table());
+
+        let mut declared = Vec::new();
+        for table in tables {
+            for (key, value) in table {
+                let package = value.get("package").and_then(|package| package.as_str());
+                let crate_name = package.unwrap_or(key);
+                if crate_name == name || key == name {
+                    declared.push(Declared {
+                        key,
+                        name: crate_name,
+                        value,
+                    });
+                }
+            }
+        }
+        declared
+    }
+
+    /// The requirement of a declared registry dependency, following `workspace = true` to the
+    /// workspace root, or `None` if it is a path or git dependency
+    #[culpa::throws]
+    #[fn_error_context::context("reading the declaration of {}", declared.key)]
+    fn requirement(&self, declared: &Declared<'_>) -> Option<VersionReq> {
+        let value = if declared.value.get("workspace").and_then(|v| v.as_bool()) == Some(true) {
+            self.root
+                .as_ref()
+                .and_then(|root| root.get("workspace"))
+                .and_then(|workspace| workspace.get("dependencies"))
+                .and_then(|dependencies| dependencies.get(declared.key))
+                .context("inherited dependency not found in workspace root")?
+        } else {
+            declared.value
+        };
+        match value {
+            toml::Value::String(req) => Some(req.parse()?),
+            toml::Value::Table(table) => {
+                if table.contains_key("registry") || table.contains_key("registry-index") {
+                    culpa::throw!(anyhow!(
+                        "dependencies from named registries are not supported, use a fully qualified spec"
+                    ));
+                }
+                if let Some(kind) = ["path", "git"]
+                    .into_iter()
+                    .find(|kind| table.contains_key(*kind))
+                {
+                    tracing::info!(
+                        "skipping the declaration of {}, {kind} dependencies cannot be downloaded",
+                        declared.key
+                    );
+                    return None;
+                }
+                let req = table
+                    .get("version")
+                    .and_then(|v| v.as_str())
+                    .context("missing version requirement")?;
+                Some(req.parse()?)
+            }
+            _ => culpa::throw!(anyhow!("unexpected dependency declaration")),
+        }
+    }
+
+    /// Resolves the spec to the version(s) the workspace uses, preferring locked versions and
+    /// falling back to the manifest's requirement
+    #[culpa::throws]
+    #[fn_error_context::context("resolving {} from the workspace", spec)]
+    pub(crate) fn resolve(&self, spec: &PackageIdSpec) -> Vec<Locked> {
+        let declared = self.declared(&spec.name.0);
+        let names = if declared.is_empty() {
+            vec![spec.name.0.as_str()]
+        } else {
+            Vec::from_iter(declared.iter().map(|declared| declared.name))
+        };
+
+        if let Some((lockfile, path)) = &self.lockfile {
+            let mut locked = Vec::new();
+            for package in &lockfile.package {
+                if !names.contains(&package.name.as_str())
+                    || spec
+                        .version_req
+                        .as_ref()
+                        .is_some_and(|req| !req.matches(&Version::parse(&package.version)))
+                {
+                    continue;
+                }
+                if package.source.is_none() {
+                    tracing::info!(
+                        "skipping {} {}, path packages cannot be downloaded",
+                        package.name,
+                        package.version
+                    );
+                    continue;
+                }
+                locked.push(package);
+            }
+            if locked.len() > 1 {
+                tracing::info!(
+                    "{} is locked at multiple versions in {} ({}), downloading all of them",
+                    spec.name,
+                    path.display(),
+                    Vec::from_iter(locked.iter().map(|package| package.version.as_str()))
+                        .join(", ")
+                );
+            }
+            if !locked.is_empty() {
+                return locked
+                    .into_iter()
+                    .map(|package| lockfile.lock(package))
+                    .collect::<Result<_, _>>()?;
+            }
+        }
+
+        if declared.is_empty() {
+            culpa::throw!(anyhow!("not a dependency of the workspace"));
+        }
+        // A crate declared in several dependency tables uses the first registry declaration
+        for declared in &declared {
+            if let Some(req) = self.requirement(declared)? {
+                return vec![Locked {
+                    spec: PackageIdSpec {
+                        source: None,
+                        name: declared.name.parse()?,
+                        version_req: spec.version_req.clone().or(Some(req)),
+                    },
+                    checksum: None,
+                    from_lockfile: false,
+                }];
+            }
+        }
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Workspace;
+    use crate::lockfile::Locked;
+    use std::path::Path;
+
+    /// Writes the files, relative to the directory
+    fn write(dir: &Path, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+    }
+
+    /// Resolves the spec in a workspace with the manifest and optional lockfile
+    fn resolve(manifest: &str, lockfile: Option<&str>, spec: &str) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), &[("Cargo.toml", manifest)]);
+        if let Some(lockfile) = lockfile {
+            write(dir.path(), &[("Cargo.lock", lockfile)]);
+        }
+        specs(
+            Workspace::find(dir.path())
+                .unwrap()
+                .resolve(&spec.parse().unwrap())
+                .unwrap(),
+        )
+    }
+
+    fn specs(locked: Vec<Locked>) -> Vec<String> {
+        Vec::from_iter(locked.into_iter().map(|locked| locked.spec.to_string()))
+    }
+
+    const LOCKFILE: &str = r#"
+        [[package]]
+        name = "app"
+        version = "0.1.0"
+
+        [[package]]
+        name = "rand"
+        version = "0.7.3"
+        source = "registry+https://github.com/rust-lang/crates.io-index"
+
+        [[package]]
+        name = "rand"
+        version = "0.8.5"
+        source = "registry+https://github.com/rust-lang/crates.io-index"
+    "#;
+
+    #[test]
+    fn requirements() {
+        let manifest = r#"
+            [dependencies]
+            serde = "1.0.100"
+            json = { package = "serde_json", version = "1.0" }
+
+            [target.'cfg(unix)'.dependencies]
+            libc = { version = "0.2", default-features = false }
+        "#;
+        assert_eq!(resolve(manifest, None, "serde"), ["serde@^1.0.100"]);
+        assert_eq!(
+            resolve(manifest, None, "serde@=1.0.200"),
+            ["serde@=1.0.200"]
+        );
+        assert_eq!(resolve(manifest, None, "serde_json"), ["serde_json@^1.0"]);
+        assert_eq!(resolve(manifest, None, "json"), ["serde_json@^1.0"]);
+        assert_eq!(resolve(manifest, None, "libc"), ["libc@^0.2"]);
+    }
+
+    #[test]
+    fn inherited_requirements() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            &[
+                (
+                    "Cargo.toml",
+                    r#"
+                    [workspace]
+                    members = ["member"]
+
+                    [workspace.dependencies]
+                    serde = { version = "1.0.100", features = ["derive"] }
+                    "#,
+                ),
+                (
+                    "member/Cargo.toml",
+                    r#"
+                    [dependencies]
+                    serde = { workspace = true }
+                    "#,
+                ),
+            ],
+        );
+        let workspace = Workspace::find(&dir.path().join("member")).unwrap();
+        assert_eq!(
+            specs(workspace.resolve(&"serde".parse().unwrap()).unwrap()),
+            ["serde@^1.0.100"]
+        );
+    }
+
+    #[test]
+    fn locked_versions() {
+        let manifest = r#"
+            [dependencies]
+            random = { package = "rand", version = "0.8" }
+        "#;
+        let crates_io = crate::package_id_spec::Source::crates_io();
+        assert_eq!(
+            resolve(manifest, Some(LOCKFILE), "rand"),
+            [
+                format!("{crates_io}#rand@=0.7.3"),
+                format!("{crates_io}#rand@=0.8.5")
+            ]
+        );
+        assert_eq!(
+            resolve(manifest, Some(LOCKFILE), "random@0.8"),
+            [format!("{crates_io}#rand@=0.8.5")]
+        );
+        assert_eq!(
+            resolve(manifest, Some(LOCKFILE), "random@0.9"),
+            ["rand@^0.9"]
+        );
+    }
+
+    #[test]
+    fn skips_path_and_git_dependencies() {
+        let manifest = r#"
+            [dependencies]
+            local = { path = "../local", version = "0.1" }
+            remote = { git = "https://example.com/remote.git" }
+
+            [dev-dependencies]
+            local = "0.1"
+        "#;
+        assert!(resolve(manifest, None, "remote").is_empty());
+        assert_eq!(resolve(manifest, None, "local"), ["local@^0.1"]);
+        let lockfile = r#"
+            [[package]]
+            name = "local"
+            version = "0.1.0"
+        "#;
+        assert_eq!(resolve(manifest, Some(lockfile), "local"), ["local@^0.1"]);
+    }
+}